@@ -0,0 +1,79 @@
+use crate::reader;
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+};
+
+/// What `decode_files` found in one file.
+#[derive(Debug, Clone)]
+pub struct StreamSummary {
+    pub path: PathBuf,
+    /// Content types agreed by START.
+    pub content_types: HashSet<String>,
+    /// Number of data frames.
+    pub frames: u64,
+    /// Total payload bytes of the data frames.
+    pub bytes: u64,
+}
+
+/// Decode uni-directional stream files in parallel on up to `threads`
+/// worker threads, calling `f` with the summary of each file read to
+/// STOP. Files that fail or end without STOP are returned along with
+/// their error; order follows completion, not `paths`.
+pub fn decode_files<I, P, F>(paths: I, threads: usize, f: F) -> Vec<(PathBuf, io::Error)>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+    F: Fn(StreamSummary) + Send + Sync,
+{
+    let queue: VecDeque<PathBuf> = paths
+        .into_iter()
+        .map(|path| path.as_ref().to_path_buf())
+        .collect();
+    let workers = threads.max(1).min(queue.len());
+    let queue = Mutex::new(queue);
+    let errors = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let path = match queue.lock().unwrap().pop_front() {
+                    Some(path) => path,
+                    None => break,
+                };
+                match summarize(&path) {
+                    Ok(summary) => f(summary),
+                    Err(err) => errors.lock().unwrap().push((path, err)),
+                }
+            });
+        }
+    });
+    errors.into_inner().unwrap()
+}
+
+fn summarize(path: &Path) -> io::Result<StreamSummary> {
+    let file = BufReader::new(File::open(path)?);
+    let mut reader = reader::reader(file).start()?;
+    let mut frames = 0;
+    let mut bytes = 0;
+    while let Some(mut frame) = reader.read_frame()? {
+        frames += 1;
+        bytes += frame.size() as u64;
+        frame.skip()?;
+    }
+    if !reader.is_stopped() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "EOF before STOP",
+        ));
+    }
+    Ok(StreamSummary {
+        path: path.to_path_buf(),
+        content_types: reader.content_types().clone(),
+        frames,
+        bytes,
+    })
+}
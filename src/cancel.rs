@@ -0,0 +1,52 @@
+use std::{
+    error, fmt, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Cooperative cancellation shared across readers and threads. Blocking
+/// operations check it between reads and fail with `Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Return the `Cancelled` error if cancelled.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::other(Cancelled))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Error inside the `io::Error` returned for a cancelled operation.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Whether the error comes from a cancelled `CancelToken`.
+pub fn is_cancelled(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<Cancelled>())
+}
@@ -0,0 +1,56 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Result, Write},
+};
+
+/// Read bytes already taken off a stream, e.g. peeked chunks for
+/// protocol detection, before reading the stream itself. Chunks are not
+/// copied together. Writes go straight to the stream.
+pub struct Chained<R> {
+    chunks: VecDeque<Vec<u8>>,
+    pos: usize,
+    inner: R,
+}
+
+impl<R> Chained<R> {
+    pub fn new(chunks: Vec<Vec<u8>>, inner: R) -> Self {
+        Self {
+            chunks: chunks.into(),
+            pos: 0,
+            inner,
+        }
+    }
+
+    /// Return the stream. Unread bytes of the chunks are lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Chained<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while let Some(chunk) = self.chunks.front() {
+            let rest = &chunk[self.pos..];
+            if rest.is_empty() {
+                self.chunks.pop_front();
+                self.pos = 0;
+                continue;
+            }
+            let n = rest.len().min(buf.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Write> Write for Chained<R> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,61 @@
+use crate::CancelToken;
+use std::{
+    io::{Read, Result},
+    thread,
+    time::Duration,
+};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Read a file that is still being written, like `tail -f`.
+///
+/// On EOF, sleep and retry instead of returning 0, so the fstrm reader
+/// sees a not-yet-written frame as pending rather than truncated. The
+/// reader stops reading at STOP, so wrapping a STOP-terminated stream
+/// does not block forever; otherwise stop it with a `CancelToken`.
+pub struct Follow<R> {
+    inner: R,
+    interval: Duration,
+    cancel: Option<CancelToken>,
+}
+
+impl<R> Follow<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            interval: DEFAULT_INTERVAL,
+            cancel: None,
+        }
+    }
+
+    /// How long to wait before retrying on EOF.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Stop waiting at EOF once `token` is cancelled.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Follow<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let n = self.inner.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            if let Some(cancel) = &self.cancel {
+                cancel.check()?;
+            }
+            thread::sleep(self.interval);
+        }
+    }
+}
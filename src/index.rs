@@ -0,0 +1,122 @@
+use crate::reader::ControlType;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io::{self, ErrorKind, Read, Result, Seek, SeekFrom};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Data,
+    Control(ControlType),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameEntry {
+    pub index: usize,
+    /// Offset of the frame's first byte, from where the index was built.
+    pub offset: u64,
+    /// Length of the whole frame, including its own header.
+    pub length: u64,
+    pub kind: FrameKind,
+}
+
+impl FrameEntry {
+    /// Offset and length of the payload (data) or body after the control
+    /// type (control).
+    fn body(&self) -> (u64, u64) {
+        let header = match self.kind {
+            FrameKind::Data => 4,
+            FrameKind::Control(_) => 12,
+        };
+        (self.offset + header, self.length - header)
+    }
+}
+
+/// Position of every frame in a seekable stream, built in a single pass
+/// without reading any payload.
+#[derive(Debug, Default)]
+pub struct StreamIndex {
+    base: u64,
+    entries: Vec<FrameEntry>,
+}
+
+/// Read a length, or None on clean EOF.
+fn read_length<R: Read>(reader: &mut R) -> Result<Option<u32>> {
+    match reader.read_u32::<BigEndian>() {
+        Ok(len) => Ok(Some(len)),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl StreamIndex {
+    /// Index all frames from the current position of `reader` to EOF.
+    pub fn build<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let base = reader.stream_position()?;
+        let mut offset = 0;
+        let mut entries = vec![];
+        while let Some(len) = read_length(reader)? {
+            let (length, kind) = if len > 0 {
+                reader.seek(SeekFrom::Current(len.into()))?;
+                (4 + u64::from(len), FrameKind::Data)
+            } else {
+                let len = reader.read_u32::<BigEndian>()?;
+                if len < 4 {
+                    return Err(io::Error::other("control frame too small"));
+                }
+                let typ = reader.read_u32::<BigEndian>()?.into();
+                reader.seek(SeekFrom::Current(i64::from(len) - 4))?;
+                (8 + u64::from(len), FrameKind::Control(typ))
+            };
+            entries.push(FrameEntry {
+                index: entries.len(),
+                offset,
+                length,
+                kind,
+            });
+            offset += length;
+        }
+        // Seeking past the end does not fail, so check for truncation here
+        let end = reader.seek(SeekFrom::End(0))?;
+        if end != base + offset {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Self { base, entries })
+    }
+
+    pub fn entries(&self) -> &[FrameEntry] {
+        &self.entries
+    }
+
+    /// The n-th frame, counting from zero.
+    pub fn get(&self, n: usize) -> Option<&FrameEntry> {
+        self.entries.get(n)
+    }
+
+    /// The n-th data frame, counting from zero.
+    pub fn data(&self, n: usize) -> Option<&FrameEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.kind == FrameKind::Data)
+            .nth(n)
+    }
+
+    /// The frame that starts at `offset`.
+    pub fn at_offset(&self, offset: u64) -> Option<&FrameEntry> {
+        self.entries
+            .binary_search_by_key(&offset, |entry| entry.offset)
+            .ok()
+            .map(|n| &self.entries[n])
+    }
+
+    /// Seek to and read back the payload of a data frame, or the body of
+    /// a control frame after its type.
+    pub fn read_body<R: Read + Seek>(&self, reader: &mut R, entry: &FrameEntry) -> Result<Vec<u8>> {
+        let (offset, len) = entry.body();
+        reader.seek(SeekFrom::Start(self.base + offset))?;
+        let mut buf = Vec::new();
+        reader.take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+}
@@ -1,6 +1,25 @@
+pub mod batch;
+pub mod cancel;
+pub mod chain;
+pub mod follow;
+pub mod index;
+pub mod message;
 pub mod reader;
+pub mod relay;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timed;
+pub mod writer;
 
-pub use reader::FstrmReader;
+pub use cancel::CancelToken;
+pub use chain::Chained;
+pub use follow::Follow;
+pub use index::StreamIndex;
+pub use message::MessageFramedReader;
+pub use reader::{probe, Frame, FstrmReader};
+pub use relay::{throttled_relay, Relay};
+pub use timed::TimedReader;
+pub use writer::planned_stream_size;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,69 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{self, ErrorKind, Read, Result};
+
+/// Adapt a message-oriented transport, where each message carries
+/// exactly one whole fstrm frame (with its length prefix), into
+/// `io::Read` for `reader()`.
+///
+/// A message holding anything but a single complete frame fails the
+/// read with `InvalidData`, instead of silently desyncing the parser.
+pub struct MessageFramedReader<I> {
+    messages: I,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl<I> MessageFramedReader<I>
+where
+    I: Iterator<Item = Vec<u8>>,
+{
+    pub fn new<T>(messages: T) -> Self
+    where
+        T: IntoIterator<IntoIter = I>,
+    {
+        Self {
+            messages: messages.into_iter(),
+            current: vec![],
+            pos: 0,
+        }
+    }
+}
+
+/// Length of the frame a message should contain, judged by its header.
+fn frame_length(msg: &[u8]) -> Option<usize> {
+    if msg.len() < 4 {
+        return None;
+    }
+    match BigEndian::read_u32(msg) as usize {
+        0 if msg.len() < 8 => None,
+        0 => (BigEndian::read_u32(&msg[4..]) as usize).checked_add(8),
+        size => size.checked_add(4),
+    }
+}
+
+impl<I> Read for MessageFramedReader<I>
+where
+    I: Iterator<Item = Vec<u8>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos == self.current.len() {
+            let msg = match self.messages.next() {
+                Some(msg) => msg,
+                None => return Ok(0),
+            };
+            if frame_length(&msg) != Some(msg.len()) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "message does not hold exactly one frame",
+                ));
+            }
+            self.current = msg;
+            self.pos = 0;
+        }
+        let remaining = &self.current[self.pos..];
+        let n = buf.len().min(remaining.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -1,17 +1,22 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use log::{info, trace, warn};
+use crate::{writer, CancelToken, Chained};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{info, log_enabled, trace, warn, Level};
 use std::{
     cmp::min,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     convert::TryInto,
-    io::{self, ErrorKind, Read, Result, Write},
-    iter::FromIterator,
+    fmt,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Result, Write},
     marker::PhantomData,
+    str,
 };
 
 // Constants copy from `fstrm/control.h`
 const CONTROL_FRAME_LENGTH_MAX: usize = 512;
+// Inclusive, as in libfstrm: a 256-byte content type is valid
 const CONTROL_FIELD_CONTENT_TYPE_LENGTH_MAX: usize = 256;
+// Fallback for field types we do not understand
+const CONTROL_FIELD_UNKNOWN_LENGTH_MAX: usize = 256;
 
 const CONTROL_TYPE_ACCEPT: u32 = 0x01;
 const CONTROL_TYPE_START: u32 = 0x02;
@@ -20,6 +25,9 @@ const CONTROL_TYPE_READY: u32 = 0x04;
 const CONTROL_TYPE_FINISH: u32 = 0x05;
 
 const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+// Non-standard: frame and byte counts carried by STOP
+const CONTROL_FIELD_STATS: u32 = 0x7374_6174;
+const CONTROL_FIELD_STATS_LENGTH: usize = 16;
 
 pub mod states {
     pub struct Ready;
@@ -38,19 +46,236 @@ pub mod states {
 pub struct FstrmReader<R, S> {
     reader: R,
     state: PhantomData<S>,
-    content_types: HashSet<String>,
+    session: Session,
+    opts: Options,
+    handshake: Vec<ControlFrame>,
+    stopped: bool,
+    // START of the current sub-stream was refused; drop its data frames
+    rejected: bool,
+    observed_bidirectional: bool,
+    // Bytes left over from a truncated data frame
+    pending_skip: u64,
+    stop_stats: Option<StreamStats>,
+    // Stream offset where the next frame starts
+    offset: u64,
+    // Control frame header read ahead by `read_coalesced`
+    held: Option<FrameHeader>,
+    // Error met by `read_coalesced` after it had collected some data,
+    // returned by the next read
+    deferred_error: Option<io::Error>,
+    // Bytes written back but not yet taken by the writer
+    write_queue: Vec<u8>,
+}
+
+/// Decoding error along with the stream offset of the frame or control
+/// field it was found in, wrapped in `io::Error` of the same kind.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub offset: u64,
+    source: io::Error,
+}
+
+impl DecodeError {
+    fn wrap(source: io::Error, offset: u64) -> io::Error {
+        io::Error::new(source.kind(), DecodeError { offset, source })
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at offset {})", self.source, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Stream offset of a decoding error returned by this crate, if known.
+pub fn error_offset(err: &io::Error) -> Option<u64> {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<DecodeError>())
+        .map(|err| err.offset)
+}
+
+type HeaderHook = Box<dyn FnMut(&FrameHeader) + Send>;
+
+// Report progress each time this many more payload bytes are read
+const PROGRESS_INTERVAL: u64 = 64 * 1024;
+// Default size of each read when buffering a whole data frame
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+// Default number of payload bytes hex-dumped at trace level
+const TRACE_BYTES: usize = 64;
+
+struct Progress {
+    hook: Box<dyn FnMut(u64) + Send>,
+    total: u64,
+    reported: u64,
+}
+
+impl Progress {
+    fn advance(&mut self, n: usize) {
+        self.total += n as u64;
+        if self.total - self.reported >= PROGRESS_INTERVAL {
+            self.reported = self.total;
+            (self.hook)(self.total);
+        }
+    }
+}
+
+/// Settings carried across the states of a reader.
+struct Options {
+    accept: AcceptPolicy,
+    denied_content_types: HashSet<String>,
+    negotiated_content_type_length_max: usize,
+    multi_stream: bool,
+    only_content_type: Option<String>,
+    lenient_control: bool,
+    strict_terminal: bool,
+    discard_content_type: bool,
+    data_frame_length_max: usize,
+    control_frame_length_max: usize,
+    read_chunk_size: usize,
+    trace_bytes: usize,
+    frame_size_policy: FrameSizePolicy,
+    field_length_max: HashMap<u32, usize>,
+    capture_handshake: bool,
+    write_queue_max: Option<usize>,
+    length_width: LengthWidth,
+    little_endian: bool,
+    on_header: Option<HeaderHook>,
+    progress: Option<Progress>,
+    cancel: Option<CancelToken>,
+}
+
+/// What to do with a data frame longer than `max_data_frame_length`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameSizePolicy {
+    /// Fail the read.
+    Error,
+    /// Return only the first `keep` bytes, and discard the rest.
+    Truncate { keep: usize },
+    /// Discard the frame and read the next one.
+    Skip,
+}
+
+/// Width of frame length fields (including the control frame escape).
+///
+/// The fstrm spec mandates `U32`. Other widths break spec compliance and
+/// are only for talking to non-standard peers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LengthWidth {
+    U16,
+    U32,
+    U64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        let mut field_length_max = HashMap::new();
+        field_length_max.insert(
+            CONTROL_FIELD_CONTENT_TYPE,
+            CONTROL_FIELD_CONTENT_TYPE_LENGTH_MAX,
+        );
+        Self {
+            accept: AcceptPolicy::Any,
+            denied_content_types: HashSet::new(),
+            negotiated_content_type_length_max: CONTROL_FIELD_CONTENT_TYPE_LENGTH_MAX,
+            multi_stream: false,
+            only_content_type: None,
+            lenient_control: false,
+            strict_terminal: false,
+            discard_content_type: false,
+            data_frame_length_max: usize::MAX,
+            control_frame_length_max: CONTROL_FRAME_LENGTH_MAX,
+            read_chunk_size: READ_CHUNK_SIZE,
+            trace_bytes: TRACE_BYTES,
+            frame_size_policy: FrameSizePolicy::Error,
+            field_length_max,
+            capture_handshake: false,
+            write_queue_max: None,
+            length_width: LengthWidth::U32,
+            little_endian: false,
+            on_header: None,
+            progress: None,
+            cancel: None,
+        }
+    }
+}
+
+impl Options {
+    /// Keep offered types that are accepted and not denied; denial always
+    /// wins. Only `AcceptPolicy::Any` accepts bare control frames that
+    /// carry no content type at all.
+    fn negotiate<'t>(&self, types: &[&'t str]) -> Result<HashSet<&'t str>> {
+        let offered = types.len();
+        let set: HashSet<_> = types
+            .iter()
+            .copied()
+            .filter(|typ| typ.len() <= self.negotiated_content_type_length_max)
+            .filter(|typ| self.accept.accepts(typ))
+            .filter(|typ| !self.denied_content_types.contains(*typ))
+            .collect();
+        let any = matches!(self.accept, AcceptPolicy::Any);
+        if set.is_empty() && (offered > 0 || !any) {
+            Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "content types mismatched",
+            ))
+        } else {
+            Ok(set)
+        }
+    }
+
+    /// Negotiated types in order of preference: as listed in
+    /// `AcceptPolicy::OneOf`, otherwise as `offered`.
+    fn by_preference(&self, offered: &[&str], negotiated: &HashSet<&str>) -> Vec<String> {
+        let mut types: Vec<String> = vec![];
+        for &typ in offered {
+            if negotiated.contains(typ) && !types.iter().any(|t| t == typ) {
+                types.push(typ.to_string());
+            }
+        }
+        if let AcceptPolicy::OneOf(preferred) = &self.accept {
+            types.sort_by_key(|typ| preferred.iter().position(|t| t == typ));
+        }
+        types
+    }
+
+    fn field_length_max(&self, field_type: u32) -> usize {
+        self.field_length_max
+            .get(&field_type)
+            .copied()
+            .unwrap_or(CONTROL_FIELD_UNKNOWN_LENGTH_MAX)
+    }
 }
 
 /// Create a new reader that accpets all content types.
 pub fn reader<R>(reader: R) -> FstrmReader<R, states::Ready> {
-    FstrmReader {
-        reader,
-        state: PhantomData,
-        content_types: HashSet::new(),
-    }
+    reader_with_policy(reader, AcceptPolicy::Any)
+}
+
+/// Create a new reader that accepts all content types, buffering `inner`
+/// with a `BufReader` of given capacity. Whole data frames are still read
+/// in chunks of `read_chunk_size`; chunks not smaller than the capacity
+/// go directly to `inner`.
+pub fn buffered_reader<R: Read>(
+    inner: R,
+    capacity: usize,
+) -> FstrmReader<BufReader<R>, states::Ready> {
+    reader(BufReader::with_capacity(capacity, inner))
+}
+
+/// Create a new reader that accepts all content types, reading `chunks`
+/// in order before `inner`. See `Chained`.
+pub fn reader_chained<R>(chunks: Vec<Vec<u8>>, inner: R) -> FstrmReader<Chained<R>, states::Ready> {
+    reader(Chained::new(chunks, inner))
 }
 
 /// Create a new reader that accepts only given set of content types.
+/// An empty set accepts all content types, same as `AcceptPolicy::Any`.
 pub fn reader_for_content_types<R, T>(
     reader: R,
     allowed_content_types: T,
@@ -58,10 +283,54 @@ pub fn reader_for_content_types<R, T>(
 where
     T: IntoIterator<Item = String>,
 {
+    let types: Vec<_> = allowed_content_types.into_iter().collect();
+    let policy = if types.is_empty() {
+        AcceptPolicy::Any
+    } else {
+        AcceptPolicy::OneOf(types)
+    };
+    reader_with_policy(reader, policy)
+}
+
+/// Create a new reader that accepts content types by given policy.
+pub fn reader_with_policy<R>(reader: R, policy: AcceptPolicy) -> FstrmReader<R, states::Ready> {
     FstrmReader {
         reader,
         state: PhantomData,
-        content_types: HashSet::from_iter(allowed_content_types),
+        session: Session::default(),
+        opts: Options {
+            accept: policy,
+            ..Default::default()
+        },
+        handshake: vec![],
+        stopped: false,
+        rejected: false,
+        observed_bidirectional: false,
+        pending_skip: 0,
+        stop_stats: None,
+        offset: 0,
+        held: None,
+        deferred_error: None,
+        write_queue: vec![],
+    }
+}
+
+/// Which content types a reader accepts.
+pub enum AcceptPolicy {
+    /// Any content type, or none at all.
+    Any,
+    /// Only listed ones, most preferred first.
+    OneOf(Vec<String>),
+    Matching(Box<dyn Fn(&str) -> bool + Send>),
+}
+
+impl AcceptPolicy {
+    fn accepts(&self, typ: &str) -> bool {
+        match self {
+            AcceptPolicy::Any => true,
+            AcceptPolicy::OneOf(types) => types.iter().any(|t| t == typ),
+            AcceptPolicy::Matching(f) => f(typ),
+        }
     }
 }
 
@@ -69,39 +338,347 @@ impl<R, S> FstrmReader<R, S> {
     pub fn into_inner(self) -> R {
         self.reader
     }
+
+    /// How this stream was opened, uni-directional until `accept()`.
+    pub fn direction(&self) -> Direction {
+        self.session.direction
+    }
+
+    /// Whether the handshake was bi-directional, so writing back to the
+    /// sender (ACCEPT, FINISH, `write_data_checked`) is allowed.
+    pub fn is_bidirectional(&self) -> bool {
+        self.session.direction == Direction::BiDirectional
+    }
+
+    /// Whether any control frame seen so far only appears in
+    /// bi-directional streams (READY, ACCEPT or FINISH), regardless of
+    /// how this reader was driven.
+    pub fn observed_bidirectional(&self) -> bool {
+        self.observed_bidirectional
+    }
+
+    /// Control frames exchanged so far, in order. Always empty unless
+    /// `capture_handshake(true)` was set.
+    pub fn handshake(&self) -> &[ControlFrame] {
+        &self.handshake
+    }
+
+    fn length_bytes(&self) -> usize {
+        match self.opts.length_width {
+            LengthWidth::U16 => 2,
+            LengthWidth::U32 => 4,
+            LengthWidth::U64 => 8,
+        }
+    }
+
+    fn into_state<T>(self) -> FstrmReader<R, T> {
+        FstrmReader {
+            reader: self.reader,
+            state: PhantomData,
+            session: self.session,
+            opts: self.opts,
+            handshake: self.handshake,
+            stopped: self.stopped,
+            rejected: self.rejected,
+            observed_bidirectional: self.observed_bidirectional,
+            pending_skip: self.pending_skip,
+            stop_stats: self.stop_stats,
+            offset: self.offset,
+            held: self.held,
+            deferred_error: self.deferred_error,
+            write_queue: self.write_queue,
+        }
+    }
+
+    fn record(&mut self, frame: ControlFrame) {
+        if self.opts.capture_handshake {
+            self.handshake.push(frame);
+        }
+    }
 }
 
-fn intersect_content_types(
-    src: HashSet<String>,
-    types: HashSet<String>,
-) -> Result<HashSet<String>> {
-    let set = if src.is_empty() {
-        types
-    } else {
-        HashSet::from_iter(src.intersection(&types).cloned())
-    };
-    if set.is_empty() {
-        Err(io::Error::new(
-            ErrorKind::InvalidData,
-            "content types mismatched",
-        ))
-    } else {
-        Ok(set)
+impl<R> FstrmReader<R, states::Ready> {
+    /// Limit the length of control fields of given type. Fields of types
+    /// without a limit set are capped at 256 bytes, as is content type.
+    pub fn max_field_length(mut self, field_type: u32, max: usize) -> Self {
+        self.opts.field_length_max.insert(field_type, max);
+        self
+    }
+
+    /// Use non-standard width for frame lengths. See `LengthWidth`. Only
+    /// the reader and its replies honor it, not the `writer` module.
+    pub fn length_width(mut self, width: LengthWidth) -> Self {
+        self.opts.length_width = width;
+        self
+    }
+
+    /// Read and write frame lengths (including the control frame escape)
+    /// as little-endian. Control types and fields stay big-endian. This
+    /// is not fstrm, only for salvaging captures of a buggy producer; the
+    /// `writer` module always emits big-endian lengths.
+    pub fn little_endian(mut self, enabled: bool) -> Self {
+        self.opts.little_endian = enabled;
+        self
+    }
+
+    /// Call `f` with every frame header read, before its body. Cheaper
+    /// than decoding frames if only counting them.
+    pub fn on_header<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&FrameHeader) + Send + 'static,
+    {
+        self.opts.on_header = Some(Box::new(f));
+        self
+    }
+
+    /// Reject given content types, even if they are allowed.
+    pub fn deny_content_types<T>(mut self, content_types: T) -> Self
+    where
+        T: IntoIterator<Item = String>,
+    {
+        self.opts.denied_content_types.extend(content_types);
+        self
+    }
+
+    /// Refuse to negotiate content types longer than `max` bytes. This is
+    /// a policy on top of the format limit set by `max_field_length`.
+    pub fn max_negotiated_content_type_len(mut self, max: usize) -> Self {
+        self.opts.negotiated_content_type_length_max = max;
+        self
+    }
+
+    /// Raise or lower the content type limit, 256 bytes by default, for
+    /// both decoding and negotiation.
+    pub fn max_content_type_length(mut self, max: usize) -> Self {
+        self.opts
+            .field_length_max
+            .insert(CONTROL_FIELD_CONTENT_TYPE, max);
+        self.opts.negotiated_content_type_length_max = max;
+        self
+    }
+
+    /// Limit the length of control frames, 512 bytes by default. Peers
+    /// sending many or long fields may need more.
+    pub fn max_control_frame_length(mut self, max: usize) -> Self {
+        self.opts.control_frame_length_max = max;
+        self
+    }
+
+    /// Read whole data frames (`read_string`, `read_payload`, ...) with
+    /// underlying reads of at most `size` bytes, 8 KiB by default. Larger
+    /// chunks mean fewer syscalls on an unbuffered source, smaller ones
+    /// less memory committed ahead of a slow peer. Chunks as large as the
+    /// `BufReader` capacity or larger bypass its buffer.
+    pub fn read_chunk_size(mut self, size: usize) -> Self {
+        self.opts.read_chunk_size = size.max(1);
+        self
+    }
+
+    /// Hex-dump up to `limit` bytes of each frame read in full at trace
+    /// level, 64 by default. Zero turns it off.
+    pub fn trace_bytes(mut self, limit: usize) -> Self {
+        self.opts.trace_bytes = limit;
+        self
+    }
+
+    /// Limit the length of data frames, handled by `frame_size_policy`.
+    /// Unlimited by default.
+    pub fn max_data_frame_length(mut self, max: usize) -> Self {
+        self.opts.data_frame_length_max = max;
+        self
+    }
+
+    pub fn frame_size_policy(mut self, policy: FrameSizePolicy) -> Self {
+        self.opts.frame_size_policy = policy;
+        self
+    }
+
+    /// Take START after data frames as the beginning of a new sub-stream
+    /// and renegotiate content types, instead of failing the read.
+    ///
+    /// STOP then ends only the sub-stream; reading goes on to the next
+    /// START, until EOF. Meant for files with concatenated streams, not
+    /// for a live peer waiting for FINISH after its STOP.
+    pub fn multi_stream(mut self, enabled: bool) -> Self {
+        self.opts.multi_stream = enabled;
+        self
+    }
+
+    /// Yield only data frames of sub-streams started with given content
+    /// type, discarding the others without buffering them. Mostly useful
+    /// with `multi_stream(true)`.
+    pub fn read_only_content_type(mut self, content_type: String) -> Self {
+        self.opts.only_content_type = Some(content_type);
+        self
+    }
+
+    /// Call `f` with the cumulative number of payload bytes read, every
+    /// 64 KiB or so, for showing progress of large reads.
+    pub fn on_progress<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        self.opts.progress = Some(Progress {
+            hook: Box::new(f),
+            total: 0,
+            reported: 0,
+        });
+        self
+    }
+
+    /// On EOF inside a control frame, keep the fields decoded so far and
+    /// mark the frame truncated, instead of failing. For salvaging
+    /// damaged captures.
+    pub fn lenient_control(mut self, enabled: bool) -> Self {
+        self.opts.lenient_control = enabled;
+        self
+    }
+
+    /// Require EOF right after STOP, failing the read of STOP otherwise,
+    /// to catch appended garbage or unexpected concatenated streams.
+    /// Only for files: a live peer keeps the connection open.
+    /// Ignored with `multi_stream(true)`.
+    pub fn strict_terminal(mut self, enabled: bool) -> Self {
+        self.opts.strict_terminal = enabled;
+        self
+    }
+
+    /// Check content types of START against the policy but do not keep
+    /// them, leaving `content_types()` empty, to save allocations on busy
+    /// servers that have no use for them. They are checked as borrowed
+    /// from the frame and never copied, so START frames handed out (see
+    /// `capture_handshake`) carry none. `start` rejects it along with
+    /// `read_only_content_type`, and `write_data_checked` fails without
+    /// the types.
+    pub fn discard_content_type(mut self, enabled: bool) -> Self {
+        self.opts.discard_content_type = enabled;
+        self
+    }
+
+    /// Check `token` before reading each frame, failing the read with
+    /// `cancel::Cancelled` once it is cancelled.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.opts.cancel = Some(token);
+        self
+    }
+
+    /// Keep the READY, ACCEPT and START frames for `handshake()`.
+    pub fn capture_handshake(mut self, enabled: bool) -> Self {
+        self.opts.capture_handshake = enabled;
+        self
+    }
+
+    /// Queue up to `max` bytes of data frames written back to the sender
+    /// instead of blocking on a full non-blocking socket; see
+    /// `flush_writes`. A frame larger than `max` is only taken into an
+    /// empty queue. Handshake replies are still written straight.
+    pub fn queue_writes(mut self, max: usize) -> Self {
+        self.opts.write_queue_max = Some(max);
+        self
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Direction {
+    #[default]
+    UniDirectional,
+    BiDirectional,
+}
+
+/// Everything negotiated during the handshake. This side is always the
+/// responder, receiving data frames.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub direction: Direction,
+    /// Content types replied in ACCEPT, empty if uni-directional.
+    pub accepted_content_types: HashSet<String>,
+    /// Content types agreed by START.
+    pub content_types: HashSet<String>,
+    /// The most preferred of `content_types`, see `AcceptPolicy::OneOf`.
+    pub content_type: Option<String>,
+}
+
+/// What the opening control frame of a stream looks like.
+#[derive(Debug)]
+pub struct Probe {
+    /// None if the stream does not open with START or READY.
+    pub direction: Option<Direction>,
+    pub content_types: Vec<String>,
+}
+
+impl Probe {
+    pub fn is_valid(&self) -> bool {
+        self.direction.is_some()
+    }
+}
+
+/// Read only the first control frame to tell whether it is a valid
+/// fstrm stream, without consuming anything after it.
+pub fn probe<R: Read>(reader: &mut R) -> Result<Probe> {
+    let frame = self::reader(reader).read_control_frame()?;
+    let direction = match frame.typ {
+        ControlType::Start => Some(Direction::UniDirectional),
+        ControlType::Ready => Some(Direction::BiDirectional),
+        _ => None,
+    };
+    Ok(Probe {
+        direction,
+        content_types: frame
+            .content_types()
+            .into_iter()
+            .map(String::from)
+            .collect(),
+    })
+}
+
 impl<R: Read, S: states::BeforeStart> FstrmReader<R, S> {
     /// Read the START frame.
     pub fn start(mut self) -> Result<FstrmReader<R, states::Started>> {
-        let frame = self.read_control_frame()?;
+        if self.opts.discard_content_type && self.opts.only_content_type.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "discard_content_type conflicts with read_only_content_type",
+            ));
+        }
+        let (typ, size) = self.read_control_header()?;
+        let frame = self.read_control_body_with(typ, size, |this, types| {
+            if typ == ControlType::Start {
+                this.negotiate_start(types)
+            } else {
+                Ok(true)
+            }
+        })?;
         frame.assert_type(ControlType::Start)?;
-        let types = frame.content_types();
-        let content_types = intersect_content_types(self.content_types, types)?;
-        Ok(FstrmReader {
-            reader: self.reader,
-            state: PhantomData,
-            content_types,
-        })
+        self.record(frame);
+        Ok(self.into_state())
+    }
+}
+
+impl<R, S> FstrmReader<R, S> {
+    /// Settle content types of the stream started by a START offering
+    /// `offered`. Return whether START should keep its content types.
+    fn negotiate_start(&mut self, offered: &[&str]) -> Result<bool> {
+        let mut negotiated = self.opts.negotiate(offered)?;
+        // START may only pick among the types replied in ACCEPT
+        let accepted = &self.session.accepted_content_types;
+        if self.session.direction == Direction::BiDirectional && !accepted.is_empty() {
+            negotiated.retain(|typ| accepted.contains(*typ));
+            if negotiated.is_empty() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "content types mismatched",
+                ));
+            }
+        }
+        if self.opts.discard_content_type {
+            self.session.content_types.clear();
+            self.session.content_type = None;
+        } else {
+            let preferred = self.opts.by_preference(offered, &negotiated);
+            self.session.content_type = preferred.into_iter().next();
+            self.session.content_types = negotiated.into_iter().map(String::from).collect();
+        }
+        Ok(!self.opts.discard_content_type)
     }
 }
 
@@ -110,40 +687,151 @@ impl<R: Read + Write> FstrmReader<R, states::Ready> {
     pub fn accept(mut self) -> Result<FstrmReader<R, states::Accepted>> {
         let frame = self.read_control_frame()?;
         frame.assert_type(ControlType::Ready)?;
-        let types = frame.content_types();
-        let content_types = intersect_content_types(self.content_types, types)?;
+        let offered = frame.content_types();
+        let accepted = self.opts.negotiate(&offered)?;
+        let ordered = self.opts.by_preference(&offered, &accepted);
+        let accepted: HashSet<String> = accepted.into_iter().map(String::from).collect();
+        self.record(frame);
 
-        let mut buf = Vec::with_capacity(12);
-        buf.write_u32::<BigEndian>(CONTROL_TYPE_ACCEPT)?;
-        for typ in content_types.iter() {
-            buf.write_u32::<BigEndian>(CONTROL_FIELD_CONTENT_TYPE)?;
-            buf.write_u32::<BigEndian>(typ.len() as u32)?;
-            buf.write_all(typ.as_bytes())?;
+        let accept = ControlFrame::new(ControlType::Accept, ordered);
+        let body = accept.encode_body()?;
+        self.write_length(0)?; // escape
+        self.write_length(body.len())?;
+        self.reader.write_all(&body)?;
+        self.record(accept);
+
+        self.session.direction = Direction::BiDirectional;
+        self.session.accepted_content_types = accepted;
+        Ok(self.into_state())
+    }
+}
+
+impl<R, S> FstrmReader<R, S> {
+    /// Encode a frame length into the start of `buf`, return its width.
+    fn encode_length(&self, len: usize, buf: &mut [u8; 8]) -> Result<usize> {
+        let width = self.length_bytes();
+        let too_large = || io::Error::new(ErrorKind::InvalidInput, "frame length overflow");
+        let len: u64 = len.try_into().map_err(|_| too_large())?;
+        if width < 8 && len >> (width * 8) != 0 {
+            return Err(too_large());
+        }
+        if self.opts.little_endian {
+            LittleEndian::write_uint(buf, len, width);
+        } else {
+            BigEndian::write_uint(buf, len, width);
         }
-        self.reader.write_u32::<BigEndian>(0)?; // escape
-        self.reader.write_u32::<BigEndian>(buf.len() as u32)?;
-        self.reader.write_all(&buf)?;
+        Ok(width)
+    }
+}
 
-        Ok(FstrmReader {
-            reader: self.reader,
-            state: PhantomData,
-            content_types,
-        })
+impl<R: Write, S> FstrmReader<R, S> {
+    fn write_length(&mut self, len: usize) -> Result<()> {
+        let mut buf = [0u8; 8];
+        let width = self.encode_length(len, &mut buf)?;
+        self.reader.write_all(&buf[..width])
     }
 }
 
 impl<R: Read + Write, S: states::AfterReady> FstrmReader<R, S> {
-    /// Write FINISH frame to sender, return the inner reader.
+    /// Write FINISH frame to sender, return the inner reader. Frames
+    /// still queued (see `queue_writes`) are written first, blocking.
     pub fn finish(mut self) -> Result<R> {
-        self.reader.write_u32::<BigEndian>(0)?; // escape
-        self.reader.write_u32::<BigEndian>(4)?; // length
+        let queued = std::mem::take(&mut self.write_queue);
+        self.reader.write_all(&queued)?;
+        self.write_length(0)?; // escape
+        self.write_length(4)?; // length
         self.reader.write_u32::<BigEndian>(CONTROL_TYPE_FINISH)?;
         Ok(self.reader)
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum ControlType {
+impl<R: Write> FstrmReader<R, states::Started> {
+    /// Write a data frame back to the sender of a bi-directional stream,
+    /// under the negotiated content type. Not part of fstrm, where only
+    /// the sender writes data; both ends must agree on it. Fail if the
+    /// stream is uni-directional or no content type was negotiated.
+    pub fn write_data_checked(&mut self, payload: &[u8]) -> Result<()> {
+        if !self.is_bidirectional() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "cannot write back to a uni-directional stream",
+            ));
+        }
+        if self.session.content_types.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "no content type negotiated",
+            ));
+        }
+        if payload.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "data frame cannot be empty",
+            ));
+        }
+        let max = match self.opts.write_queue_max {
+            Some(max) => max,
+            None => {
+                self.write_length(payload.len())?;
+                return self.reader.write_all(payload);
+            }
+        };
+        let mut len = [0u8; 8];
+        let width = self.encode_length(payload.len(), &mut len)?;
+        if self.write_queue.len() + width + payload.len() > max && !self.flush_writes()? {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "write queue full"));
+        }
+        self.write_queue.extend_from_slice(&len[..width]);
+        self.write_queue.extend_from_slice(payload);
+        self.flush_writes().map(|_| ())
+    }
+
+    /// Write out what `queue_writes` queued, as far as the writer takes
+    /// it without blocking. Return true once the queue is empty and the
+    /// writer flushed, false if it would block.
+    pub fn flush_writes(&mut self) -> Result<bool> {
+        while !self.write_queue.is_empty() {
+            match self.reader.write(&self.write_queue) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.write_queue.drain(..n);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        match self.reader.flush() {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Bytes queued by `queue_writes` not written out yet.
+    pub fn queued_writes(&self) -> usize {
+        self.write_queue.len()
+    }
+
+    /// Write a frame back to the sender, see `write_data_checked`. Only
+    /// data frames can be sent; control frames are up to the handshake.
+    ///
+    /// With a socket, reads and writes can interleave freely, e.g. one
+    /// reply per request read, as long as the sender reads its replies
+    /// before waiting for FINISH.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        match frame {
+            Frame::Data(payload) => self.write_data_checked(payload),
+            Frame::Control(frame) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot write control frame {:?} back", frame.typ),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlType {
     Accept,
     Start,
     Stop,
@@ -152,9 +840,42 @@ enum ControlType {
     Unknown(u32),
 }
 
-enum FrameHeader {
-    Data { size: usize },
-    Control { size: usize, typ: ControlType },
+impl fmt::Display for ControlType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlType::Accept => write!(f, "FSTRM_CONTROL_ACCEPT"),
+            ControlType::Start => write!(f, "FSTRM_CONTROL_START"),
+            ControlType::Stop => write!(f, "FSTRM_CONTROL_STOP"),
+            ControlType::Ready => write!(f, "FSTRM_CONTROL_READY"),
+            ControlType::Finish => write!(f, "FSTRM_CONTROL_FINISH"),
+            ControlType::Unknown(typ) => write!(f, "FSTRM_CONTROL_UNKNOWN ({:#x})", typ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameHeader {
+    Data {
+        size: usize,
+    },
+    /// `size` counts the control frame body after its type.
+    Control {
+        size: usize,
+        typ: ControlType,
+    },
+}
+
+impl From<ControlType> for u32 {
+    fn from(typ: ControlType) -> Self {
+        match typ {
+            ControlType::Accept => CONTROL_TYPE_ACCEPT,
+            ControlType::Start => CONTROL_TYPE_START,
+            ControlType::Stop => CONTROL_TYPE_STOP,
+            ControlType::Ready => CONTROL_TYPE_READY,
+            ControlType::Finish => CONTROL_TYPE_FINISH,
+            ControlType::Unknown(value) => value,
+        }
+    }
 }
 
 impl From<u32> for ControlType {
@@ -172,22 +893,120 @@ impl From<u32> for ControlType {
 
 impl<R: Read, S> FstrmReader<R, S> {
     fn next_length(&mut self) -> Result<usize> {
-        Ok(self.reader.read_u32::<BigEndian>()?.try_into().unwrap())
+        self.try_next_length()?
+            .ok_or_else(|| self.decode_error(ErrorKind::UnexpectedEof.into()))
+    }
+
+    /// Like `next_length` but return None on EOF before its first byte.
+    fn try_next_length(&mut self) -> Result<Option<usize>> {
+        let width = self.length_bytes();
+        let mut buf = [0u8; 8];
+        let mut n = 0;
+        while n < width {
+            match self.reader.read(&mut buf[n..width]) {
+                Ok(0) if n == 0 => return Ok(None),
+                Ok(0) => return Err(self.decode_error(ErrorKind::UnexpectedEof.into())),
+                Ok(m) => n += m,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        let len = if self.opts.little_endian {
+            LittleEndian::read_uint(&buf, width)
+        } else {
+            BigEndian::read_uint(&buf, width)
+        };
+        len.try_into().map(Some).map_err(|_| {
+            self.decode_error(io::Error::new(
+                ErrorKind::InvalidData,
+                "frame length overflow",
+            ))
+        })
+    }
+
+    /// Attach the offset of the current frame to an error found while
+    /// decoding it. Errors of the inner reader are left as they are.
+    fn decode_error(&self, err: io::Error) -> io::Error {
+        DecodeError::wrap(err, self.offset)
+    }
+
+    fn expect_eof(&mut self) -> Result<()> {
+        let mut buf = [0u8; 1];
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "trailing bytes after STOP",
+                    ))
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn discard(&mut self, len: u64) -> Result<()> {
+        let n = io::copy(&mut self.reader.by_ref().take(len), &mut io::sink())?;
+        if n < len {
+            Err(ErrorKind::UnexpectedEof.into())
+        } else {
+            Ok(())
+        }
     }
 
     fn read_frame_header(&mut self) -> Result<FrameHeader> {
-        let size = self.next_length()?;
+        self.try_read_frame_header()?
+            .ok_or_else(|| ErrorKind::UnexpectedEof.into())
+    }
+
+    /// Read the next frame header, or None on EOF right at its start.
+    fn try_read_frame_header(&mut self) -> Result<Option<FrameHeader>> {
+        if let Some(cancel) = &self.opts.cancel {
+            cancel.check()?;
+        }
+        let header = match self.try_next_length()? {
+            Some(size) => self.decode_frame_header(size)?,
+            None => return Ok(None),
+        };
+        let width = self.length_bytes() as u64;
+        self.offset += match header {
+            FrameHeader::Data { size } => width + size as u64,
+            FrameHeader::Control { size, .. } => 2 * width + 4 + size as u64,
+        };
+        if let FrameHeader::Control {
+            typ: ControlType::Ready | ControlType::Accept | ControlType::Finish,
+            ..
+        } = header
+        {
+            self.observed_bidirectional = true;
+        }
+        if let Some(on_header) = self.opts.on_header.as_mut() {
+            on_header(&header);
+        }
+        Ok(Some(header))
+    }
+
+    fn decode_frame_header(&mut self, size: usize) -> Result<FrameHeader> {
         if size > 0 {
             trace!("data frame ({} bytes)", size);
             Ok(FrameHeader::Data { size })
         } else {
             let size = self.next_length()?;
-            if size > CONTROL_FRAME_LENGTH_MAX {
-                Err(io::Error::new(ErrorKind::Other, "control frame too large"))
+            if size > self.opts.control_frame_length_max {
+                Err(self.decode_error(io::Error::other("control frame too large")))
             } else if size < 4 {
-                Err(io::Error::new(ErrorKind::Other, "control frame too small"))
+                Err(self.decode_error(io::Error::other("control frame too small")))
             } else {
-                let typ = self.reader.read_u32::<BigEndian>()?.into();
+                let typ = match self.reader.read_u32::<BigEndian>() {
+                    Ok(typ) => typ.into(),
+                    // Short read, not a failure of the inner reader
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                        return Err(self.decode_error(err))
+                    }
+                    Err(err) => return Err(err),
+                };
                 trace!("control frame {:?} ({} bytes)", typ, size);
                 Ok(FrameHeader::Control {
                     size: size - 4,
@@ -197,82 +1016,697 @@ impl<R: Read, S> FstrmReader<R, S> {
         }
     }
 
-    fn read_control_frame(&mut self) -> Result<ControlFrame> {
-        let (typ, size) = match self.read_frame_header()? {
-            FrameHeader::Data { .. } => {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "unexpected data frame",
-                ))
+    pub(crate) fn read_control_frame(&mut self) -> Result<ControlFrame> {
+        let (typ, size) = self.read_control_header()?;
+        self.read_control_body(typ, size)
+    }
+
+    fn read_control_header(&mut self) -> Result<(ControlType, usize)> {
+        match self.read_frame_header()? {
+            FrameHeader::Data { .. } => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "unexpected data frame",
+            )),
+            FrameHeader::Control { typ, size } => Ok((typ, size)),
+        }
+    }
+
+    fn read_control_body(&mut self, typ: ControlType, size: usize) -> Result<ControlFrame> {
+        self.read_control_body_with(typ, size, |_, _| Ok(true))
+    }
+
+    /// Read a control frame body. `check` sees its content types while
+    /// they are still borrowed from the body; they are copied into the
+    /// frame only if it returns true.
+    fn read_control_body_with<F>(
+        &mut self,
+        typ: ControlType,
+        size: usize,
+        check: F,
+    ) -> Result<ControlFrame>
+    where
+        F: FnOnce(&mut Self, &[&str]) -> Result<bool>,
+    {
+        let body_offset = self.offset - size as u64;
+        let mut frame = Vec::with_capacity(size);
+        self.reader
+            .by_ref()
+            .take(size as u64)
+            .read_to_end(&mut frame)?;
+        trace_hex("control frame", &frame, self.opts.trace_bytes);
+        let truncated = frame.len() < size;
+        if truncated {
+            if !self.opts.lenient_control {
+                let offset = body_offset + frame.len() as u64;
+                return Err(DecodeError::wrap(ErrorKind::UnexpectedEof.into(), offset));
             }
-            FrameHeader::Control { typ, size } => (typ, size),
-        };
-        let mut frame = vec![0u8; size];
-        self.reader.read_exact(&mut frame)?;
+            warn!("control frame truncated ({}/{} bytes)", frame.len(), size);
+        }
+
+        let mut field_start = 0;
+        let fields = self
+            .decode_fields(&frame, truncated, &mut field_start)
+            .map_err(|err| DecodeError::wrap(err, body_offset + field_start as u64))?;
+        let types: Vec<&str> = fields
+            .iter()
+            .filter_map(|field| match field {
+                RawField::ContentType(typ) => Some(*typ),
+                RawField::Other(_) => None,
+            })
+            .collect();
+        let keep_types = check(self, &types)?;
+        let fields = fields
+            .into_iter()
+            .filter_map(|field| match field {
+                RawField::ContentType(typ) if keep_types => {
+                    Some(ControlFrameField::ContentType(typ.to_string()))
+                }
+                RawField::ContentType(_) => None,
+                RawField::Other(field) => Some(field),
+            })
+            .collect();
+        Ok(ControlFrame {
+            typ,
+            fields,
+            truncated,
+        })
+    }
 
-        let mut buf = &frame[..];
-        let mut fields: Vec<ControlFrameField> = vec![];
+    /// Decode fields of a control frame body. `field_start` is left at
+    /// the position of the last field tried, for error reporting.
+    fn decode_fields<'f>(
+        &self,
+        frame: &'f [u8],
+        truncated: bool,
+        field_start: &mut usize,
+    ) -> Result<Vec<RawField<'f>>> {
+        let mut buf = frame;
+        let mut fields = vec![];
         while !buf.is_empty() {
+            *field_start = frame.len() - buf.len();
+            if truncated && buf.len() < 8 {
+                break;
+            }
             let field_type = buf.read_u32::<BigEndian>()?;
             let size = buf.read_u32::<BigEndian>()? as usize;
-            if size > buf.len() || size > CONTROL_FIELD_CONTENT_TYPE_LENGTH_MAX {
-                warn!("paring error: control field too long");
-                return Err(ErrorKind::UnexpectedEof.into());
+            if size > self.opts.field_length_max(field_type) {
+                warn!("paring error: control field {} too long", field_type);
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "control field too long",
+                ));
+            }
+            if size > buf.len() && truncated {
+                break;
+            }
+            if size > buf.len() {
+                warn!("paring error: control field exceeds frame");
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "control field exceeds frame ({} bytes, {} remaining)",
+                        size,
+                        buf.len()
+                    ),
+                ));
             }
             let (field_content, remaining) = buf.split_at(size);
             buf = remaining;
             let field = match field_type {
                 CONTROL_FIELD_CONTENT_TYPE => {
-                    let typ = String::from_utf8(field_content.to_vec()).map_err(|_| {
+                    let typ = str::from_utf8(field_content).map_err(|_| {
                         io::Error::new(ErrorKind::InvalidData, "content type with invalid utf-8")
                     })?;
-                    ControlFrameField::ContentType(typ)
+                    RawField::ContentType(typ)
+                }
+                CONTROL_FIELD_STATS if field_content.len() == CONTROL_FIELD_STATS_LENGTH => {
+                    RawField::Other(ControlFrameField::Stats(StreamStats {
+                        frames: BigEndian::read_u64(&field_content[..8]),
+                        bytes: BigEndian::read_u64(&field_content[8..]),
+                    }))
                 }
                 typ => {
                     info!("unknown control field: {}", field_type);
-                    ControlFrameField::Unknown(typ)
+                    RawField::Other(ControlFrameField::Unknown(typ))
                 }
             };
             fields.push(field);
         }
-        Ok(ControlFrame { typ, fields })
+        Ok(fields)
     }
 }
 
 impl<R> FstrmReader<R, states::Started> {
     /// Negotiated content types
     pub fn content_types(&self) -> &HashSet<String> {
-        &self.content_types
+        &self.session.content_types
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// The negotiated content type to decode data frames as, in case
+    /// START carried several.
+    pub fn content_type(&self) -> Option<&str> {
+        self.session.content_type.as_deref()
+    }
+
+    /// Whether STOP was read. False after the end of a stream means the
+    /// peer closed it right after a frame without stopping.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Statistics carried by the last STOP read, if its writer added
+    /// them. See `StreamStats`.
+    pub fn stop_stats(&self) -> Option<StreamStats> {
+        self.stop_stats
     }
 }
 
 impl<R: Read> FstrmReader<R, states::Started> {
     /// Read the next data frame, return None if the other side
-    /// stop sending with a control frame.
-    pub fn read_frame(&mut self) -> Result<Option<DataFrame<R>>> {
-        match self.read_frame_header()? {
-            FrameHeader::Data { size } => Ok(Some(DataFrame::new(&mut self.reader, size))),
-            FrameHeader::Control { typ, .. } if typ == ControlType::Stop => Ok(None),
-            FrameHeader::Control { typ, .. } => Err(io::Error::new(
+    /// stop sending with a control frame, or closed the stream between
+    /// frames without one (see `is_stopped`). EOF inside a frame is
+    /// still `UnexpectedEof`.
+    pub fn read_frame(&mut self) -> Result<Option<DataFrame<'_, R>>> {
+        loop {
+            match self.read_next()? {
+                Next::Data(size) => return Ok(Some(self.data_frame(size))),
+                Next::End => return Ok(None),
+                Next::Held => unreachable!(),
+                Next::Control(frame) => {
+                    if !self.read_on_after(&frame)? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether `read_frame` goes on to the next frame after `frame`, or
+    /// ends there.
+    fn read_on_after(&self, frame: &ControlFrame) -> Result<bool> {
+        match frame.typ {
+            ControlType::Stop => Ok(self.opts.multi_stream),
+            ControlType::Start if self.opts.multi_stream => Ok(true),
+            typ => Err(io::Error::new(
                 ErrorKind::InvalidData,
                 format!("unexpected control frame {:?}", typ),
             )),
         }
     }
+
+    fn data_frame(&mut self, size: usize) -> DataFrame<'_, R> {
+        let (chunk, trace) = (self.opts.read_chunk_size, self.opts.trace_bytes);
+        let progress = self.opts.progress.as_mut();
+        DataFrame::new(&mut self.reader, size, chunk, trace, progress)
+    }
+
+    /// Read the next data frame into `buf`, replacing its content, so one
+    /// allocation can be reused for the whole stream. Return the frame
+    /// length, or None on STOP.
+    pub fn read_frame_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        buf.clear();
+        match self.read_frame()? {
+            Some(mut frame) => frame.read_to_end(buf).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Copy the next data frame into `w` as it is read, without holding
+    /// it in memory. Return its length, or None on STOP.
+    pub fn read_frame_to_writer<W: Write>(&mut self, w: &mut W) -> Result<Option<u64>> {
+        match self.read_frame()? {
+            Some(mut frame) => io::copy(&mut frame, w).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether data frames of the current (sub-)stream are wanted.
+    fn selected(&self) -> bool {
+        if self.rejected {
+            return false;
+        }
+        match &self.opts.only_content_type {
+            Some(typ) => self.session.content_types.contains(typ),
+            None => true,
+        }
+    }
+
+    /// Read the next frame, data or control, with its content. Control
+    /// frames are returned as they come, so extensions that interleave
+    /// them with data can be handled without losing position. STOP is
+    /// returned once, then None.
+    pub fn read_next_frame(&mut self) -> Result<Option<Frame>> {
+        match self.read_next()? {
+            Next::Data(size) => {
+                let mut buf = Vec::new();
+                self.data_frame(size).read_to_end(&mut buf)?;
+                Ok(Some(Frame::Data(buf)))
+            }
+            Next::Control(frame) => Ok(Some(Frame::Control(frame))),
+            Next::End => Ok(None),
+            Next::Held => unreachable!(),
+        }
+    }
+
+    /// Read up to the next data frame header or through the next control
+    /// frame, applying the frame size policy and multi-stream START.
+    fn read_next(&mut self) -> Result<Next> {
+        self.read_next_or_hold(false)
+    }
+
+    /// Like `read_next`, but with `hold_control` leave a control frame
+    /// unread for the next call, returning `Next::Held`.
+    fn read_next_or_hold(&mut self, hold_control: bool) -> Result<Next> {
+        if let Some(err) = self.deferred_error.take() {
+            return Err(err);
+        }
+        if self.pending_skip > 0 {
+            self.discard(self.pending_skip)?;
+            self.pending_skip = 0;
+        }
+        loop {
+            let header = if let Some(header) = self.held.take() {
+                header
+            } else if !self.stopped || self.opts.multi_stream {
+                // At a frame boundary: another sub-stream may follow, or
+                // the peer may have gone without STOP
+                match self.try_read_frame_header()? {
+                    Some(header) => header,
+                    None if self.stopped => return Ok(Next::End),
+                    None => {
+                        warn!("EOF before STOP");
+                        return Ok(Next::End);
+                    }
+                }
+            } else {
+                return Ok(Next::End);
+            };
+            if self.stopped
+                && !matches!(
+                    header,
+                    FrameHeader::Control {
+                        typ: ControlType::Start,
+                        ..
+                    }
+                )
+            {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("expect START after STOP but {:?} received", header),
+                ));
+            }
+            if hold_control {
+                if let FrameHeader::Control { .. } = header {
+                    self.held = Some(header);
+                    return Ok(Next::Held);
+                }
+            }
+            match header {
+                FrameHeader::Data { size } if size > self.opts.data_frame_length_max => {
+                    warn!("data frame too large ({} bytes)", size);
+                    match self.opts.frame_size_policy {
+                        FrameSizePolicy::Error => {
+                            return Err(io::Error::new(
+                                ErrorKind::InvalidData,
+                                "data frame too large",
+                            ))
+                        }
+                        FrameSizePolicy::Skip => self.discard(size as u64)?,
+                        FrameSizePolicy::Truncate { keep } => {
+                            let keep = keep.min(size);
+                            self.pending_skip = (size - keep) as u64;
+                            return Ok(Next::Data(keep));
+                        }
+                    }
+                }
+                FrameHeader::Data { size } if !self.selected() => self.discard(size as u64)?,
+                FrameHeader::Data { size } => return Ok(Next::Data(size)),
+                FrameHeader::Control { typ, size } => {
+                    let frame = if typ == ControlType::Start && self.opts.multi_stream {
+                        self.read_control_body_with(typ, size, Self::restart)?
+                    } else {
+                        self.read_control_body(typ, size)?
+                    };
+                    match typ {
+                        ControlType::Stop => {
+                            self.stopped = true;
+                            self.stop_stats = frame.stats();
+                            if self.opts.strict_terminal && !self.opts.multi_stream {
+                                self.expect_eof()?;
+                            }
+                        }
+                        ControlType::Start if self.opts.multi_stream => {
+                            info!("new sub-stream started: {:?}", self.session.content_types);
+                        }
+                        _ => (),
+                    }
+                    return Ok(Next::Control(frame));
+                }
+            }
+        }
+    }
+
+    /// Negotiate START of a new sub-stream, see `negotiate_start`.
+    fn restart(&mut self, offered: &[&str]) -> Result<bool> {
+        self.stopped = false;
+        match self.negotiate_start(offered) {
+            Ok(keep_types) => {
+                self.rejected = false;
+                Ok(keep_types)
+            }
+            Err(err) => {
+                // Discard data until the next START
+                self.rejected = true;
+                self.session.content_types.clear();
+                self.session.content_type = None;
+                Err(err)
+            }
+        }
+    }
+
+    /// Read the next data frame as UTF-8 text, return None on STOP.
+    pub fn read_string(&mut self) -> Result<Option<String>> {
+        let mut buf = Vec::new();
+        match self.read_frame()? {
+            Some(mut frame) => frame.read_to_end(&mut buf)?,
+            None => return Ok(None),
+        };
+        String::from_utf8(buf)
+            .map(Some)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "data frame with invalid utf-8"))
+    }
+
+    /// Like `read_string` but replace invalid UTF-8 with U+FFFD.
+    pub fn read_string_lossy(&mut self) -> Result<Option<String>> {
+        let mut buf = Vec::new();
+        match self.read_frame()? {
+            Some(mut frame) => frame.read_to_end(&mut buf)?,
+            None => return Ok(None),
+        };
+        Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+    }
+
+    /// Concatenate consecutive data frames until at least `target_bytes`
+    /// are collected or a control frame is reached, so that a batch never
+    /// spans sub-streams. Return None after STOP. An error met after some
+    /// data was collected is returned by the next call, the batch first.
+    pub fn read_coalesced(&mut self, target_bytes: usize) -> Result<Option<Vec<u8>>> {
+        let mut batch = Vec::new();
+        while batch.is_empty() || batch.len() < target_bytes {
+            let hold = !batch.is_empty();
+            let len = batch.len();
+            let read = match self.read_next_or_hold(hold) {
+                Ok(Next::Data(size)) => self.data_frame(size).read_to_end(&mut batch).map(Some),
+                Ok(Next::Held) => break,
+                Ok(Next::End) => Ok(None),
+                // Control frames between batches are taken as read_frame does
+                Ok(Next::Control(frame)) => match self.read_on_after(&frame) {
+                    Ok(true) => continue,
+                    Ok(false) => Ok(None),
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            };
+            match read {
+                Ok(Some(_)) => (),
+                Ok(None) => break,
+                Err(err) if hold => {
+                    batch.truncate(len);
+                    self.deferred_error = Some(err);
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if batch.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(batch))
+        }
+    }
+
+    /// Iterate over all frames as `read_next_frame` does, ending after
+    /// STOP.
+    pub fn frames(&mut self) -> Frames<'_, R> {
+        Frames { reader: self }
+    }
+
+    /// Like `frames`, but owning the reader, e.g. to return it from a
+    /// function. Frames are yielded as they come, control ones included,
+    /// unlike `read_frame` which only returns data frames. Frames of the
+    /// handshake are already read; see `capture_handshake`.
+    pub fn into_frames(self) -> IntoFrames<R> {
+        IntoFrames { reader: self }
+    }
+
+    /// Iterate over 1 in `every` data frames, starting from the first;
+    /// the others are read through and discarded without allocating.
+    pub fn sampled(&mut self, every: u32) -> Sampled<'_, R> {
+        Sampled {
+            reader: self,
+            every: every.max(1),
+            seen: 0,
+        }
+    }
+
+    /// Read the next data frame in full. Unlike `read_frame`, a data
+    /// frame cut short by EOF is not an error but returned as partial,
+    /// so whatever arrived can be salvaged.
+    pub fn read_payload(&mut self) -> Result<Option<Payload>> {
+        let mut frame = match self.read_frame()? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let expected_len = frame.size();
+        let mut bytes = Vec::new();
+        match frame.read_to_end(&mut bytes) {
+            Ok(_) => Ok(Some(Payload::Complete(bytes))),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                warn!(
+                    "data frame truncated ({}/{} bytes)",
+                    bytes.len(),
+                    expected_len
+                );
+                Ok(Some(Payload::Partial {
+                    expected_len,
+                    bytes,
+                }))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<R: Read> FstrmReader<BufReader<R>, states::Started> {
+    /// Read the next data frame only if it is entirely buffered already,
+    /// so that the underlying reader is never touched. Return None if the
+    /// frame is incomplete, the next one is a control frame, or it needs
+    /// more than returning as-is (too large, or not of `only_content_type`);
+    /// `read_frame` then takes it from there. Only standard framing is
+    /// handled: with `length_width` or `little_endian` set, always None.
+    pub fn try_read_buffered_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        if let Some(cancel) = &self.opts.cancel {
+            cancel.check()?;
+        }
+        if self.pending_skip > 0 || self.stopped || self.held.is_some() || !self.selected() {
+            return Ok(None);
+        }
+        if self.opts.little_endian || self.opts.length_width != LengthWidth::U32 {
+            return Ok(None);
+        }
+        let buf = self.reader.buffer();
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let size = BigEndian::read_u32(buf) as usize;
+        if size == 0 || size > self.opts.data_frame_length_max || buf.len() - 4 < size {
+            return Ok(None);
+        }
+        let frame = buf[4..4 + size].to_vec();
+        self.reader.consume(4 + size);
+        self.offset += 4 + size as u64;
+        if let Some(on_header) = self.opts.on_header.as_mut() {
+            on_header(&FrameHeader::Data { size });
+        }
+        if let Some(progress) = self.opts.progress.as_mut() {
+            progress.advance(size);
+        }
+        trace!("data frame ({} bytes, buffered)", size);
+        trace_hex("data frame", &frame, self.opts.trace_bytes);
+        Ok(Some(frame))
+    }
+}
+
+/// What comes next in a started stream.
+enum Next {
+    /// Header of a data frame of given size, whose payload is unread.
+    Data(usize),
+    Control(ControlFrame),
+    /// STOP was already seen.
+    End,
+    /// A control frame header, left for the next read.
+    Held,
+}
+
+/// A whole frame, owning its content.
+#[derive(Debug)]
+pub enum Frame {
+    Data(Vec<u8>),
+    Control(ControlFrame),
+}
+
+impl Frame {
+    /// A data frame with given payload.
+    ///
+    /// ```
+    /// use fstrm::{writer, Frame};
+    ///
+    /// let frame = Frame::data(b"hello".as_slice());
+    /// let mut buf = vec![];
+    /// writer::write_frame(&mut buf, &frame).unwrap();
+    /// assert_eq!(buf, b"\0\0\0\x05hello");
+    /// ```
+    pub fn data<T: Into<Vec<u8>>>(payload: T) -> Self {
+        Frame::Data(payload.into())
+    }
+
+    /// Read one frame of any kind off `reader`, the counterpart of
+    /// `writer::write_frame`. Nothing is checked about its place in a
+    /// stream.
+    ///
+    /// ```
+    /// use fstrm::{writer, Frame};
+    ///
+    /// let mut buf = vec![];
+    /// writer::write_frame(&mut buf, &Frame::data("hello")).unwrap();
+    /// match Frame::decode(&mut &buf[..]).unwrap() {
+    ///     Frame::Data(data) => assert_eq!(data, b"hello"),
+    ///     Frame::Control(_) => unreachable!(),
+    /// }
+    /// ```
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Frame> {
+        let mut reader = self::reader(reader);
+        match reader.read_frame_header()? {
+            FrameHeader::Data { size } => {
+                let mut frame =
+                    DataFrame::new(&mut reader.reader, size, READ_CHUNK_SIZE, TRACE_BYTES, None);
+                let mut buf = Vec::new();
+                frame.read_to_end(&mut buf)?;
+                Ok(Frame::Data(buf))
+            }
+            FrameHeader::Control { typ, size } => {
+                reader.read_control_body(typ, size).map(Frame::Control)
+            }
+        }
+    }
+}
+
+impl From<&[u8]> for Frame {
+    fn from(payload: &[u8]) -> Self {
+        Frame::Data(payload.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Frame {
+    fn from(payload: Vec<u8>) -> Self {
+        Frame::Data(payload)
+    }
 }
 
+impl<const N: usize> From<[u8; N]> for Frame {
+    fn from(payload: [u8; N]) -> Self {
+        Frame::Data(payload.to_vec())
+    }
+}
+
+/// A decoded control field, content type borrowed from the frame body.
+enum RawField<'f> {
+    ContentType(&'f str),
+    Other(ControlFrameField),
+}
+
+#[derive(Debug)]
 pub enum ControlFrameField {
     ContentType(String),
+    Stats(StreamStats),
     Unknown(u32),
 }
 
+/// Summary of a stream as recorded by its writer in STOP.
+///
+/// This is an extension of this crate, not part of fstrm; other
+/// implementations ignore the field as an unknown one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StreamStats {
+    /// Number of data frames written.
+    pub frames: u64,
+    /// Total payload bytes of those frames.
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
 pub struct ControlFrame {
     typ: ControlType,
     fields: Vec<ControlFrameField>,
+    truncated: bool,
 }
 
 impl ControlFrame {
-    fn assert_type(&self, typ: ControlType) -> Result<()> {
+    pub fn new<T>(typ: ControlType, content_types: T) -> Self
+    where
+        T: IntoIterator<Item = String>,
+    {
+        Self {
+            typ,
+            fields: content_types
+                .into_iter()
+                .map(ControlFrameField::ContentType)
+                .collect(),
+            truncated: false,
+        }
+    }
+
+    /// Encode the control type and fields, without the frame header.
+    /// Unknown fields are dropped since their content is not kept.
+    pub(crate) fn encode_body(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(12);
+        buf.write_u32::<BigEndian>(self.typ.into())?;
+        for typ in self.content_types() {
+            buf.write_u32::<BigEndian>(CONTROL_FIELD_CONTENT_TYPE)?;
+            buf.write_u32::<BigEndian>(writer::length_u32(typ.len(), "content type")?)?;
+            buf.write_all(typ.as_bytes())?;
+        }
+        if let Some(stats) = self.stats() {
+            buf.write_u32::<BigEndian>(CONTROL_FIELD_STATS)?;
+            buf.write_u32::<BigEndian>(CONTROL_FIELD_STATS_LENGTH as u32)?;
+            buf.write_u64::<BigEndian>(stats.frames)?;
+            buf.write_u64::<BigEndian>(stats.bytes)?;
+        }
+        Ok(buf)
+    }
+
+    /// STOP carrying stream statistics, see `StreamStats`.
+    pub fn stop_with_stats(stats: StreamStats) -> Self {
+        Self {
+            typ: ControlType::Stop,
+            fields: vec![ControlFrameField::Stats(stats)],
+            truncated: false,
+        }
+    }
+
+    /// Statistics field of this frame, if any.
+    pub fn stats(&self) -> Option<StreamStats> {
+        self.fields.iter().find_map(|field| match field {
+            ControlFrameField::Stats(stats) => Some(*stats),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn assert_type(&self, typ: ControlType) -> Result<()> {
         if self.typ == typ {
             Ok(())
         } else {
@@ -283,29 +1717,172 @@ impl ControlFrame {
         }
     }
 
-    fn content_types(self) -> HashSet<String> {
+    /// Content types carried by this frame, in the order they appear.
+    pub fn content_types(&self) -> Vec<&str> {
         self.fields
-            .into_iter()
+            .iter()
             .filter_map(|field| match field {
-                ControlFrameField::ContentType(typ) => Some(typ),
+                ControlFrameField::ContentType(typ) => Some(typ.as_str()),
                 _ => None,
             })
             .collect()
     }
+
+    pub fn control_type(&self) -> ControlType {
+        self.typ
+    }
+
+    /// Whether EOF came before the declared end of the frame, only
+    /// possible with `lenient_control(true)`. Fields are the ones
+    /// decoded completely before EOF.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Whether both frames mean the same: same control type, content
+    /// types and stats, regardless of field order. Unknown fields are
+    /// ignored.
+    pub fn semantically_eq(&self, other: &ControlFrame) -> bool {
+        self.typ == other.typ
+            && self.stats() == other.stats()
+            && self.content_types().into_iter().collect::<HashSet<_>>()
+                == other.content_types().into_iter().collect()
+    }
+}
+
+impl fmt::Display for ControlFrameField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlFrameField::ContentType(typ) => write!(
+                f,
+                "FSTRM_CONTROL_FIELD_CONTENT_TYPE ({} bytes) {:?}",
+                typ.len(),
+                typ
+            ),
+            ControlFrameField::Stats(stats) => {
+                write!(f, "stats ({} frames, {} bytes)", stats.frames, stats.bytes)
+            }
+            ControlFrameField::Unknown(typ) => write!(f, "unknown field {:#x}", typ),
+        }
+    }
+}
+
+/// One line, e.g. `FSTRM_CONTROL_START, FSTRM_CONTROL_FIELD_CONTENT_TYPE
+/// (17 bytes) "test-content-type"`.
+impl fmt::Display for ControlFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.typ)?;
+        for field in &self.fields {
+            write!(f, ", {}", field)?;
+        }
+        if self.truncated {
+            write!(f, " (truncated)")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Frames<'a, R> {
+    reader: &'a mut FstrmReader<R, states::Started>,
+}
+
+impl<'a, R: Read> Iterator for Frames<'a, R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_next_frame().transpose()
+    }
+}
+
+pub struct IntoFrames<R> {
+    reader: FstrmReader<R, states::Started>,
+}
+
+impl<R> IntoFrames<R> {
+    pub fn into_inner(self) -> FstrmReader<R, states::Started> {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for IntoFrames<R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_next_frame().transpose()
+    }
+}
+
+pub struct Sampled<'a, R> {
+    reader: &'a mut FstrmReader<R, states::Started>,
+    every: u32,
+    seen: u32,
+}
+
+impl<'a, R: Read> Iterator for Sampled<'a, R> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut frame = match self.reader.read_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+            let pick = self.seen == 0;
+            self.seen = (self.seen + 1) % self.every;
+            if pick {
+                let mut buf = Vec::new();
+                return Some(frame.read_to_end(&mut buf).map(|_| buf));
+            } else if let Err(err) = frame.skip() {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Payload {
+    Complete(Vec<u8>),
+    /// Data frame truncated by EOF, with the bytes read before that.
+    Partial {
+        expected_len: usize,
+        bytes: Vec<u8>,
+    },
 }
 
 pub struct DataFrame<'a, R> {
     reader: &'a mut R,
     size: usize,
     pos: usize,
+    chunk: usize,
+    trace_bytes: usize,
+    progress: Option<&'a mut Progress>,
+}
+
+impl<R> fmt::Debug for DataFrame<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DataFrame")
+            .field("size", &self.size)
+            .field("pos", &self.pos)
+            .finish()
+    }
 }
 
 impl<'a, R> DataFrame<'a, R> {
-    fn new(reader: &'a mut R, size: usize) -> Self {
+    fn new(
+        reader: &'a mut R,
+        size: usize,
+        chunk: usize,
+        trace_bytes: usize,
+        progress: Option<&'a mut Progress>,
+    ) -> Self {
         Self {
             reader,
-            size: size.try_into().unwrap(),
+            size,
             pos: 0,
+            chunk,
+            trace_bytes,
+            progress,
         }
     }
 
@@ -318,15 +1895,62 @@ impl<'a, R> DataFrame<'a, R> {
     }
 }
 
+impl<'a, R: Read> DataFrame<'a, R> {
+    /// Discard the rest of the frame.
+    pub fn skip(&mut self) -> Result<()> {
+        io::copy(self, &mut io::sink())?;
+        Ok(())
+    }
+}
+
 impl<'a, R: Read> Read for DataFrame<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let max_len = min(buf.len(), self.remaining());
         let n = self.reader.read(&mut buf[..max_len])?;
         self.pos += n;
+        if let Some(progress) = self.progress.as_mut() {
+            progress.advance(n);
+        }
         if n == 0 && self.remaining() != 0 {
             Err(ErrorKind::UnexpectedEof.into())
         } else {
             Ok(n)
         }
     }
+
+    // Grow by the configured chunk size instead of guessing
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = buf.len();
+        while self.remaining() > 0 {
+            let len = buf.len();
+            buf.resize(len + min(self.chunk, self.remaining()), 0);
+            match self.read(&mut buf[len..]) {
+                Ok(n) => buf.truncate(len + n),
+                Err(err) => {
+                    buf.truncate(len);
+                    if err.kind() != ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        trace_hex("data frame", &buf[start..], self.trace_bytes);
+        Ok(buf.len() - start)
+    }
+}
+
+/// Hex-dump the head of `bytes` at trace level, if enabled.
+fn trace_hex(what: &str, bytes: &[u8], limit: usize) {
+    if limit == 0 || !log_enabled!(Level::Trace) {
+        return;
+    }
+    let mut hex: Vec<String> = bytes
+        .iter()
+        .take(limit)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if bytes.len() > limit {
+        hex.push("...".into());
+    }
+    trace!("{} bytes: {}", what, hex.join(" "));
 }
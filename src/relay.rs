@@ -0,0 +1,95 @@
+use crate::{
+    reader::{self, ControlFrame, ControlType},
+    writer, Frame,
+};
+use std::{
+    io::{self, ErrorKind, Read, Result, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Forward a uni-directional stream frame by frame, optionally rate
+/// limited or rewritten. START and STOP are passed along as read unless
+/// configured otherwise. Control frames are re-encoded, so fields
+/// unknown to this crate are dropped from them.
+#[derive(Debug, Clone, Default)]
+pub struct Relay {
+    interval: Option<Duration>,
+    strip_content_type: bool,
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send at most `frames_per_sec` data frames per second, with no
+    /// bursts.
+    pub fn frames_per_sec(mut self, frames_per_sec: u32) -> Result<Self> {
+        if frames_per_sec == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "frame rate must be positive",
+            ));
+        }
+        self.interval = Some(Duration::from_secs(1) / frames_per_sec);
+        Ok(self)
+    }
+
+    /// Write START without any content type, e.g. to share a capture
+    /// without revealing what it carries. Data frames are unchanged.
+    pub fn strip_content_type(mut self, enabled: bool) -> Self {
+        self.strip_content_type = enabled;
+        self
+    }
+
+    /// Relay from `input` to `output` until STOP, returning the number
+    /// of data frames relayed. If `input` ends without STOP, what was
+    /// relayed is flushed and `UnexpectedEof` returned; no STOP is made
+    /// up for `output`.
+    pub fn run<R: Read, W: Write>(&self, input: R, mut output: W) -> Result<u64> {
+        let mut reader = reader::reader(input).capture_handshake(true).start()?;
+        if self.strip_content_type {
+            let start = ControlFrame::new(ControlType::Start, None);
+            writer::write_control_frame(&mut output, &start)?;
+        } else {
+            for frame in reader.handshake() {
+                writer::write_control_frame(&mut output, frame)?;
+            }
+        }
+
+        let mut count = 0;
+        let mut next = Instant::now();
+        while let Some(frame) = reader.read_next_frame()? {
+            if let Frame::Data(_) = frame {
+                if let Some(interval) = self.interval {
+                    let now = Instant::now();
+                    if now < next {
+                        thread::sleep(next - now);
+                    }
+                    next = next.max(now) + interval;
+                }
+                count += 1;
+            }
+            writer::write_frame(&mut output, &frame)?;
+        }
+        output.flush()?;
+        if !reader.is_stopped() {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "EOF before STOP"));
+        }
+        Ok(count)
+    }
+}
+
+/// Forward a uni-directional stream from `input` to `output`, sending
+/// at most `frames_per_sec` data frames per second. Return the number
+/// of data frames relayed. See `Relay` for more options.
+pub fn throttled_relay<R, W>(input: R, output: W, frames_per_sec: u32) -> Result<u64>
+where
+    R: Read,
+    W: Write,
+{
+    Relay::new()
+        .frames_per_sec(frames_per_sec)?
+        .run(input, output)
+}
@@ -0,0 +1,13 @@
+use crate::{writer, Frame};
+use std::io::Cursor;
+
+/// Encode `frames` into a buffer for a reader to decode, e.g. in tests.
+///
+/// Panics if a frame cannot be encoded, such as an empty data frame.
+pub fn cursor_from_frames(frames: Vec<Frame>) -> Cursor<Vec<u8>> {
+    let mut buf = vec![];
+    for frame in &frames {
+        writer::write_frame(&mut buf, frame).expect("unencodable frame");
+    }
+    Cursor::new(buf)
+}
@@ -1,5 +1,75 @@
-use crate::reader;
-use std::io::Read;
+use crate::{
+    batch, cancel,
+    index::FrameKind,
+    reader::{
+        self, AcceptPolicy, ControlFrame, ControlType, Direction, FrameHeader, FrameSizePolicy,
+        LengthWidth, Payload, StreamStats,
+    },
+    writer, CancelToken, Follow, Frame, MessageFramedReader, StreamIndex, TimedReader,
+};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const SAMPLE: [u8; 65] = [
+    0, 0, 0, 0, 0, 0, 0, 29, // control frame, length 29
+    0, 0, 0, 2, // control type: START
+    0, 0, 0, 1, // field type: content type
+    0, 0, 0, 17, // field length 17
+    116, 101, 115, 116, 45, 99, 111, 110, 116, 101, 110, 116, 45, 116, 121, 112,
+    101, // "test-content-type"
+    0, 0, 0, 12, // data frame, length 12
+    116, 101, 115, 116, 45, 99, 111, 110, 116, 101, 110, 116, // "test-content"
+    0, 0, 0, 0, 0, 0, 0, 4, // control frame, length 4
+    0, 0, 0, 3, // control type: STOP
+];
+
+/// Encode a control frame with given content type fields.
+fn control_frame(typ: u8, content_types: &[&[u8]]) -> Vec<u8> {
+    let mut body = vec![0, 0, 0, typ];
+    for content_type in content_types {
+        body.extend_from_slice(&[0, 0, 0, 1]);
+        body.extend_from_slice(&(content_type.len() as u32).to_be_bytes());
+        body.extend_from_slice(content_type);
+    }
+    let mut frame = vec![0, 0, 0, 0];
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// Serve each chunk only after sleeping for its delay.
+struct SlowReader {
+    chunks: Vec<(Duration, Vec<u8>)>,
+    pos: usize,
+}
+
+impl Read for SlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (delay, chunk) = match self.chunks.first_mut() {
+            Some(chunk) => chunk,
+            None => return Ok(0),
+        };
+        if self.pos == 0 {
+            thread::sleep(*delay);
+        }
+        let n = buf.len().min(chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        if self.pos == chunk.len() {
+            self.chunks.remove(0);
+            self.pos = 0;
+        }
+        Ok(n)
+    }
+}
 
 #[test]
 fn test_unidirectional_reader() {
@@ -29,3 +99,1829 @@ fn test_unidirectional_reader() {
 
     assert!(reader.read_frame().unwrap().is_none());
 }
+
+#[test]
+fn test_timed_reader() {
+    let start = SAMPLE[..37].to_vec();
+    let data = vec![0, 0, 0, 1, 42];
+    let stop = SAMPLE[53..].to_vec();
+    let delay = Duration::from_millis(50);
+    let slow = SlowReader {
+        chunks: vec![
+            (Duration::default(), start),
+            (Duration::default(), data.clone()),
+            (delay, data.clone()),
+            (delay * 2, data),
+            (Duration::default(), stop),
+        ],
+        pos: 0,
+    };
+    let mut reader = TimedReader::new(reader::reader(slow).start().unwrap());
+
+    let mut gaps = vec![];
+    while let Some((gap, mut frame)) = reader.read_frame().unwrap() {
+        io::copy(&mut frame, &mut io::sink()).unwrap();
+        gaps.push(gap);
+    }
+    assert_eq!(gaps.len(), 3);
+    // upper bounds are loose for busy machines
+    let slack = Duration::from_millis(500);
+    assert!(gaps[0] < slack);
+    assert!(gaps[1] >= delay && gaps[1] < delay + slack);
+    assert!(gaps[2] >= delay * 2 && gaps[2] < delay * 2 + slack);
+}
+
+/// Read from a fixed input, collect everything written.
+struct Duplex {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Duplex {
+    fn new(input: &[u8]) -> Self {
+        Self {
+            input: io::Cursor::new(input.to_vec()),
+            output: vec![],
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl io::Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_bare_control_frames() {
+    let bytes = [
+        0, 0, 0, 0, 0, 0, 0, 4, // control frame, length 4
+        0, 0, 0, 4, // control type: READY
+        0, 0, 0, 0, 0, 0, 0, 4, // control frame, length 4
+        0, 0, 0, 2, // control type: START
+        0, 0, 0, 0, 0, 0, 0, 4, // control frame, length 4
+        0, 0, 0, 3, // control type: STOP
+    ];
+    let reader = reader::reader(Duplex::new(&bytes)).accept().unwrap();
+    let mut reader = reader.start().unwrap();
+    assert!(reader.content_types().is_empty());
+    assert!(reader.read_frame().unwrap().is_none());
+    let accept = reader.into_inner().output;
+    assert_eq!(accept, [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 1]);
+
+    let reader = reader::reader_for_content_types(&bytes[12..], vec!["test".into()]);
+    assert!(reader.start().is_err());
+}
+
+#[test]
+fn test_read_string() {
+    let mut reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().unwrap().is_none());
+
+    let mut bytes = SAMPLE.to_vec();
+    bytes[41] = 0xff;
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let err = reader.read_string().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let text = reader.read_string_lossy().unwrap().unwrap();
+    assert_eq!(text, "\u{fffd}est-content");
+}
+
+#[test]
+fn test_control_frame_content_types() {
+    let start = reader::reader(&SAMPLE[..]).read_control_frame().unwrap();
+    assert_eq!(start.content_types(), ["test-content-type"]);
+
+    for typ in [1, 4] {
+        let bytes = [
+            0, 0, 0, 0, 0, 0, 0, 30, // control frame, length 30
+            0, 0, 0, typ, // control type: ACCEPT or READY
+            0, 0, 0, 1, 0, 0, 0, 1, b'a', // content type "a"
+            0, 0, 0, 9, 0, 0, 0, 1, 0, // unknown field
+            0, 0, 0, 1, 0, 0, 0, 0, // empty content type
+        ];
+        let frame = reader::reader(&bytes[..]).read_control_frame().unwrap();
+        assert_eq!(frame.content_types(), ["a", ""]);
+    }
+}
+
+#[test]
+fn test_try_read_buffered_frame() {
+    let mut first = SAMPLE[..53].to_vec();
+    first.extend_from_slice(&[0, 0, 0, 3, b'a']);
+    let slow = SlowReader {
+        chunks: vec![
+            (Duration::default(), first),
+            (Duration::default(), vec![b'b', b'c']),
+            (Duration::default(), SAMPLE[53..].to_vec()),
+        ],
+        pos: 0,
+    };
+    let reader = reader::reader(io::BufReader::new(slow));
+    let mut reader = reader.start().unwrap();
+    let frame = reader.try_read_buffered_frame().unwrap().unwrap();
+    assert_eq!(frame, b"test-content");
+    assert!(reader.try_read_buffered_frame().unwrap().is_none());
+
+    let mut buf = vec![];
+    let mut frame = reader.read_frame().unwrap().unwrap();
+    frame.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"abc");
+    assert!(reader.read_frame().unwrap().is_none());
+
+    // progress and trace as for any other read
+    capture_logs();
+    let size = 100 * 1024;
+    let mut bytes = SAMPLE[..37].to_vec();
+    bytes.extend_from_slice(&(size as u32).to_be_bytes());
+    bytes.extend_from_slice(&[0xb0, 0xff, 0xe4, 0xed]);
+    bytes.resize(bytes.len() + size - 4, 0);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+    let counts = Arc::new(Mutex::new(vec![]));
+    let sink = counts.clone();
+    let reader = reader::reader(io::BufReader::with_capacity(2 * size, &bytes[..]))
+        .on_progress(move |n| sink.lock().unwrap().push(n))
+        .trace_bytes(4);
+    let mut reader = reader.start().unwrap();
+    assert_eq!(
+        reader.try_read_buffered_frame().unwrap().unwrap().len(),
+        size
+    );
+    assert_eq!(*counts.lock().unwrap(), [size as u64]);
+    let logs = LOGS.lock().unwrap();
+    assert!(logs
+        .iter()
+        .any(|l| l == "data frame bytes: b0 ff e4 ed ..."));
+}
+
+#[test]
+fn test_buffered_frame_deferred() {
+    let mut bytes = SAMPLE[..53].to_vec();
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'o', b'k']);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    // too large: left to the size policy of read_frame
+    let reader = reader::reader(io::BufReader::new(&bytes[..]))
+        .max_data_frame_length(4)
+        .frame_size_policy(FrameSizePolicy::Truncate { keep: 4 });
+    let mut reader = reader.start().unwrap();
+    assert!(reader.try_read_buffered_frame().unwrap().is_none());
+    assert_eq!(reader.read_string().unwrap().as_deref(), Some("test"));
+    // the rest of the truncated frame is still to be skipped
+    let mut reader = reader::reader(io::BufReader::new(&bytes[..]))
+        .max_data_frame_length(4)
+        .frame_size_policy(FrameSizePolicy::Truncate { keep: 4 })
+        .start()
+        .unwrap();
+    reader.read_frame().unwrap().unwrap().skip().unwrap();
+    assert!(reader.try_read_buffered_frame().unwrap().is_none());
+    assert_eq!(reader.read_string().unwrap().as_deref(), Some("ok"));
+    assert!(reader.read_string().unwrap().is_none());
+    assert!(reader.try_read_buffered_frame().unwrap().is_none());
+
+    // not selected
+    let mut reader = reader::reader(io::BufReader::new(&bytes[..]))
+        .read_only_content_type("other".into())
+        .start()
+        .unwrap();
+    assert!(reader.try_read_buffered_frame().unwrap().is_none());
+    assert!(reader.read_string().unwrap().is_none());
+
+    let token = CancelToken::new();
+    let reader = reader::reader(io::BufReader::new(&bytes[..])).cancel_token(token.clone());
+    let mut reader = reader.start().unwrap();
+    token.cancel();
+    let err = reader.try_read_buffered_frame().unwrap_err();
+    assert!(cancel::is_cancelled(&err));
+}
+
+#[test]
+fn test_max_field_length() {
+    let bytes = [
+        0, 0, 0, 0, 0, 0, 0, 16, // control frame, length 16
+        0, 0, 0, 2, // control type: START
+        0, 0, 0, 9, // field type: unknown
+        0, 0, 0x27, 0x10, // field length 10000
+        0, 0, 0, 0, // (truncated)
+    ];
+    let err = reader::reader(&bytes[..]).start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let bytes = [
+        0, 0, 0, 0, 0, 0, 0, 16, // control frame, length 16
+        0, 0, 0, 2, // control type: START
+        0, 0, 0, 9, // field type: unknown
+        0, 0, 0, 4, // field length 4
+        1, 2, 3, 4, // field content
+    ];
+    let reader = reader::reader(&bytes[..]).max_field_length(9, 2);
+    assert!(reader.start().is_err());
+    let reader = reader::reader(&bytes[..]).max_field_length(9, 4);
+    assert!(reader.start().is_ok());
+}
+
+#[test]
+fn test_capture_handshake() {
+    let mut bytes = vec![
+        0, 0, 0, 0, 0, 0, 0, 29, // control frame, length 29
+        0, 0, 0, 4, // control type: READY
+        0, 0, 0, 1, // field type: content type
+        0, 0, 0, 17, // field length 17
+    ];
+    bytes.extend_from_slice(b"test-content-type");
+    bytes.extend_from_slice(&SAMPLE);
+
+    let reader = reader::reader(Duplex::new(&bytes)).capture_handshake(true);
+    let reader = reader.accept().unwrap().start().unwrap();
+    let types: Vec<_> = reader
+        .handshake()
+        .iter()
+        .map(|frame| frame.control_type())
+        .collect();
+    assert_eq!(
+        types,
+        [ControlType::Ready, ControlType::Accept, ControlType::Start]
+    );
+    for frame in reader.handshake() {
+        assert_eq!(frame.content_types(), ["test-content-type"]);
+    }
+
+    let reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    assert!(reader.handshake().is_empty());
+}
+
+#[test]
+fn test_probe() {
+    let mut input = &SAMPLE[..];
+    let probe = crate::probe(&mut input).unwrap();
+    assert!(probe.is_valid());
+    assert_eq!(probe.direction, Some(Direction::UniDirectional));
+    assert_eq!(probe.content_types, ["test-content-type"]);
+    assert_eq!(input.len(), SAMPLE.len() - 37);
+
+    let probe = crate::probe(&mut &SAMPLE[53..]).unwrap();
+    assert!(!probe.is_valid());
+    assert!(crate::probe(&mut &SAMPLE[37..]).is_err());
+}
+
+#[test]
+fn test_length_width() {
+    let bytes = [
+        0, 0, 0, 13, // control frame, length 13
+        0, 0, 0, 4, // control type: READY
+        0, 0, 0, 1, 0, 0, 0, 1, b't', // content type "t"
+        0, 0, 0, 13, // control frame, length 13
+        0, 0, 0, 2, // control type: START
+        0, 0, 0, 1, 0, 0, 0, 1, b't', // content type "t"
+        0, 2, b'h', b'i', // data frame, length 2
+        0, 0, 0, 4, // control frame, length 4
+        0, 0, 0, 3, // control type: STOP
+    ];
+    let reader = reader::reader(Duplex::new(&bytes)).length_width(LengthWidth::U16);
+    let mut reader = reader.accept().unwrap().start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "hi");
+    assert!(reader.read_string().unwrap().is_none());
+    let output = reader.finish().unwrap().output;
+    assert_eq!(
+        output,
+        [
+            0, 0, 0, 13, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, b't', // ACCEPT
+            0, 0, 0, 4, 0, 0, 0, 5, // FINISH
+        ]
+    );
+
+    assert!(reader::reader(&bytes[..]).start().is_err());
+}
+
+#[test]
+fn test_repeated_ready_aborts() {
+    let ready = [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 4];
+    let bytes: Vec<u8> = ready
+        .iter()
+        .cycle()
+        .take(ready.len() * 8)
+        .copied()
+        .collect();
+    let mut duplex = Duplex::new(&bytes);
+    let reader = reader::reader(&mut duplex).accept().unwrap();
+    assert!(reader.start().is_err());
+    assert_eq!(duplex.input.position(), 24);
+    assert_eq!(duplex.output.len(), 12); // single ACCEPT
+}
+
+#[test]
+fn test_read_partial_payload() {
+    let mut reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    let payload = reader.read_payload().unwrap().unwrap();
+    assert_eq!(payload, Payload::Complete(b"test-content".to_vec()));
+    assert!(reader.read_payload().unwrap().is_none());
+
+    let mut reader = reader::reader(&SAMPLE[..46]).start().unwrap();
+    let payload = reader.read_payload().unwrap().unwrap();
+    assert_eq!(
+        payload,
+        Payload::Partial {
+            expected_len: 12,
+            bytes: b"test-".to_vec()
+        }
+    );
+}
+
+#[test]
+fn test_on_header() {
+    let headers = Arc::new(Mutex::new(vec![]));
+    let sink = headers.clone();
+    let reader = reader::reader(&SAMPLE[..]).on_header(move |header| {
+        sink.lock().unwrap().push(header.clone());
+    });
+    let mut reader = reader.start().unwrap();
+    while reader.read_payload().unwrap().is_some() {}
+    assert_eq!(
+        *headers.lock().unwrap(),
+        [
+            FrameHeader::Control {
+                size: 25,
+                typ: ControlType::Start
+            },
+            FrameHeader::Data { size: 12 },
+            FrameHeader::Control {
+                size: 0,
+                typ: ControlType::Stop
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_message_framed_reader() {
+    let messages = vec![
+        SAMPLE[..37].to_vec(),
+        SAMPLE[37..53].to_vec(),
+        SAMPLE[53..].to_vec(),
+    ];
+    let reader = reader::reader(MessageFramedReader::new(messages));
+    let mut reader = reader.start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().unwrap().is_none());
+
+    let messages = vec![SAMPLE[..20].to_vec(), SAMPLE[20..37].to_vec()];
+    let reader = reader::reader(MessageFramedReader::new(messages));
+    let err = reader.start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_multi_stream() {
+    let mut bytes = SAMPLE[..53].to_vec();
+    bytes.extend_from_slice(&[
+        0, 0, 0, 0, 0, 0, 0, 13, // control frame, length 13
+        0, 0, 0, 2, // control type: START
+        0, 0, 0, 1, 0, 0, 0, 1, b't', // content type "t"
+        0, 0, 0, 1, b'x', // data frame, length 1
+    ]);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let reader = reader::reader(&bytes[..]).multi_stream(true);
+    let mut reader = reader.start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert_eq!(reader.read_string().unwrap().unwrap(), "x");
+    assert!(reader.content_types().contains("t"));
+    assert!(reader.read_string().unwrap().is_none());
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    let err = reader.read_string().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let types = vec!["test-content-type".into()];
+    let reader = reader::reader_for_content_types(&bytes[..], types).multi_stream(true);
+    let mut reader = reader.start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().is_err());
+    // data of the refused sub-stream is never returned
+    assert!(reader.content_types().is_empty());
+    assert!(reader.read_string().unwrap().is_none());
+
+    // also without STOP in between, and until the next accepted START
+    let mut bytes = control_frame(2, &[b"good"]);
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'g', b'1']);
+    bytes.extend_from_slice(&control_frame(2, &[b"evil"]));
+    bytes.extend_from_slice(&[0, 0, 0, 1, b'x']);
+    bytes.extend_from_slice(&control_frame(2, &[b"good"]));
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'g', b'2']);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+    let reader = reader::reader_for_content_types(&bytes[..], vec!["good".into()]);
+    let mut reader = reader.multi_stream(true).start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "g1");
+    assert!(reader.read_string().is_err());
+    assert_eq!(reader.read_string().unwrap().unwrap(), "g2");
+    assert!(reader.content_types().contains("good"));
+    assert!(reader.read_string().unwrap().is_none());
+}
+
+#[test]
+fn test_content_type_length_boundary() {
+    for (len, ok) in [(255, true), (256, true), (257, false)] {
+        let content_type = vec![b'x'; len];
+        let bytes = control_frame(2, &[&content_type]);
+        let types = vec![String::from_utf8(content_type).unwrap()];
+        let reader = reader::reader_for_content_types(&bytes[..], types.clone());
+        match reader.start() {
+            Ok(reader) => {
+                assert!(ok);
+                assert_eq!(reader.content_types().iter().next(), types.first());
+            }
+            Err(err) => {
+                assert!(!ok);
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_deny_content_types() {
+    let denied = || vec!["test-content-type".to_string()];
+    let reader = reader::reader(&SAMPLE[..]).deny_content_types(denied());
+    assert!(reader.start().is_err());
+
+    let allowed = vec!["test-content-type".to_string()];
+    let reader = reader::reader_for_content_types(&SAMPLE[..], allowed);
+    assert!(reader.deny_content_types(denied()).start().is_err());
+
+    let bytes = control_frame(2, &[b"other"]);
+    let reader = reader::reader(&bytes[..]).deny_content_types(denied());
+    assert!(reader.start().unwrap().content_types().contains("other"));
+
+    let bytes = control_frame(4, &[b"test-content-type", b"other"]);
+    let reader = reader::reader(Duplex::new(&bytes)).deny_content_types(denied());
+    let reader = reader.accept().unwrap();
+    assert_eq!(reader.into_inner().output, control_frame(1, &[b"other"]));
+}
+
+#[test]
+fn test_read_coalesced() {
+    let mut bytes = SAMPLE[..37].to_vec();
+    for i in 0..10 {
+        bytes.extend_from_slice(&[0, 0, 0, 3, i, i, i]);
+    }
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let mut batches = vec![];
+    while let Some(batch) = reader.read_coalesced(8).unwrap() {
+        batches.push(batch);
+    }
+    let sizes: Vec<_> = batches.iter().map(Vec::len).collect();
+    assert_eq!(sizes, [9, 9, 9, 3]);
+    assert_eq!(batches[3], [9, 9, 9]);
+    assert!(reader.read_frame().unwrap().is_none());
+}
+
+#[test]
+fn test_read_coalesced_at_control_frame() {
+    let mut bytes = SAMPLE[..37].to_vec();
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'a', b'a', 0, 0, 0, 2, b'b', b'b']);
+    bytes.extend_from_slice(&control_frame(5, &[]));
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'c', b'c', 0, 0, 0, 5, b'd']);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    assert_eq!(reader.read_coalesced(100).unwrap().unwrap(), b"aabb");
+    let err = reader.read_coalesced(100).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    // data read before an error is returned ahead of it
+    assert_eq!(reader.read_coalesced(100).unwrap().unwrap(), b"cc");
+    let err = reader.read_coalesced(100).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+    // one batch per sub-stream
+    let mut bytes = control_frame(2, &[b"a"]);
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'a', b'a']);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+    bytes.extend_from_slice(&control_frame(2, &[b"b"]));
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'b', b'b']);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+    let mut reader = reader::reader(&bytes[..])
+        .multi_stream(true)
+        .start()
+        .unwrap();
+    assert_eq!(reader.read_coalesced(100).unwrap().unwrap(), b"aa");
+    assert!(reader.content_types().contains("a"));
+    assert_eq!(reader.read_coalesced(100).unwrap().unwrap(), b"bb");
+    assert!(reader.content_types().contains("b"));
+    assert!(reader.read_coalesced(100).unwrap().is_none());
+}
+
+#[test]
+fn test_on_progress() {
+    let size = 200 * 1024;
+    let mut bytes = SAMPLE[..37].to_vec();
+    bytes.extend_from_slice(&(size as u32).to_be_bytes());
+    bytes.resize(bytes.len() + size, 0);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let counts = Arc::new(Mutex::new(vec![]));
+    let sink = counts.clone();
+    let reader = reader::reader(&bytes[..]).on_progress(move |n| sink.lock().unwrap().push(n));
+    let mut reader = reader.start().unwrap();
+    while reader.read_payload().unwrap().is_some() {}
+
+    let counts = counts.lock().unwrap();
+    assert_eq!(counts.len(), 3);
+    assert!(counts.windows(2).all(|w| w[0] < w[1]));
+    assert!(*counts.last().unwrap() <= size as u64);
+}
+
+#[test]
+fn test_lenient_control() {
+    let mut bytes = control_frame(2, &[b"a", b"bcd"]);
+    bytes[7] += 8; // declare more than available
+    bytes.truncate(bytes.len() - 1);
+
+    let err = reader::reader(&bytes[..]).start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+    let mut reader = reader::reader(&bytes[..]).lenient_control(true);
+    let frame = reader.read_control_frame().unwrap();
+    assert!(frame.is_truncated());
+    assert_eq!(frame.content_types(), ["a"]);
+
+    let reader = reader::reader(&bytes[..]).lenient_control(true);
+    let reader = reader.capture_handshake(true).start().unwrap();
+    assert!(reader.content_types().contains("a"));
+    assert!(reader.handshake()[0].is_truncated());
+}
+
+#[test]
+fn test_stream_index() {
+    let mut cursor = io::Cursor::new(SAMPLE.to_vec());
+    let index = StreamIndex::build(&mut cursor).unwrap();
+    let kinds: Vec<_> = index.entries().iter().map(|entry| entry.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            FrameKind::Control(ControlType::Start),
+            FrameKind::Data,
+            FrameKind::Control(ControlType::Stop),
+        ]
+    );
+
+    let entry = index.at_offset(37).unwrap();
+    assert_eq!(entry, index.data(0).unwrap());
+    assert_eq!(entry.length, 16);
+    assert_eq!(
+        index.read_body(&mut cursor, entry).unwrap(),
+        b"test-content"
+    );
+    assert!(index.at_offset(38).is_none());
+    assert_eq!(index.get(2).unwrap().offset, 53);
+
+    let mut cursor = io::Cursor::new(SAMPLE[..50].to_vec());
+    assert!(StreamIndex::build(&mut cursor).is_err());
+}
+
+#[test]
+fn test_accept_policy() {
+    let bare = control_frame(2, &[]);
+    let policies = || {
+        vec![
+            (AcceptPolicy::Any, true, true),
+            (
+                AcceptPolicy::OneOf(vec!["test-content-type".into()]),
+                true,
+                false,
+            ),
+            (AcceptPolicy::OneOf(vec!["other".into()]), false, false),
+            (
+                AcceptPolicy::Matching(Box::new(|t| t.starts_with("test-"))),
+                true,
+                false,
+            ),
+            (
+                AcceptPolicy::Matching(Box::new(|t| t.is_empty())),
+                false,
+                false,
+            ),
+        ]
+    };
+    for (policy, sample_ok, _) in policies() {
+        let reader = reader::reader_with_policy(&SAMPLE[..], policy);
+        assert_eq!(reader.start().is_ok(), sample_ok);
+    }
+    for (policy, _, bare_ok) in policies() {
+        let reader = reader::reader_with_policy(&bare[..], policy);
+        assert_eq!(reader.start().is_ok(), bare_ok);
+    }
+}
+
+#[test]
+fn test_sampled() {
+    let mut bytes = SAMPLE[..37].to_vec();
+    for i in 1..=10 {
+        bytes.extend_from_slice(&[0, 0, 0, 1, i]);
+    }
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let frames: Vec<_> = reader.sampled(3).map(Result::unwrap).collect();
+    assert_eq!(frames, [[1], [4], [7], [10]]);
+    assert!(reader.read_frame().unwrap().is_none());
+}
+
+#[test]
+fn test_max_negotiated_content_type_len() {
+    let long = [b'x'; 100];
+    let bytes = control_frame(2, &[&long]);
+    let reader = reader::reader(&bytes[..]).max_negotiated_content_type_len(64);
+    let err = reader.start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(reader::reader(&bytes[..]).start().is_ok());
+
+    let bytes = control_frame(4, &[&long, b"short"]);
+    let reader = reader::reader(Duplex::new(&bytes)).max_negotiated_content_type_len(64);
+    let output = reader.accept().unwrap().into_inner().output;
+    assert_eq!(output, control_frame(1, &[b"short"]));
+}
+
+#[test]
+fn test_session() {
+    let mut bytes = control_frame(4, &[b"a", b"b", b"c"]);
+    bytes.extend_from_slice(&control_frame(2, &[b"b"]));
+    let types = vec!["a".into(), "b".into()];
+    let reader = reader::reader_for_content_types(Duplex::new(&bytes), types);
+    let reader = reader.accept().unwrap().start().unwrap();
+    let session = reader.session();
+    assert_eq!(session.direction, Direction::BiDirectional);
+    let mut accepted: Vec<_> = session.accepted_content_types.iter().collect();
+    accepted.sort();
+    assert_eq!(accepted, ["a", "b"]);
+    assert_eq!(session.content_types.len(), 1);
+    assert!(session.content_types.contains("b"));
+
+    let reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    assert_eq!(reader.session().direction, Direction::UniDirectional);
+    assert!(reader.session().accepted_content_types.is_empty());
+}
+
+#[test]
+fn test_frame_size_policy() {
+    let mut bytes = SAMPLE[..53].to_vec();
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'o', b'k']);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+    let reader = |policy| {
+        reader::reader(&bytes[..])
+            .max_data_frame_length(4)
+            .frame_size_policy(policy)
+            .start()
+            .unwrap()
+    };
+
+    let mut r = reader(FrameSizePolicy::Error);
+    assert_eq!(
+        r.read_string().unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+
+    let mut r = reader(FrameSizePolicy::Skip);
+    assert_eq!(r.read_string().unwrap().unwrap(), "ok");
+    assert!(r.read_string().unwrap().is_none());
+
+    let mut r = reader(FrameSizePolicy::Truncate { keep: 4 });
+    assert_eq!(r.read_string().unwrap().unwrap(), "test");
+    assert_eq!(r.read_string().unwrap().unwrap(), "ok");
+    assert!(r.read_string().unwrap().is_none());
+}
+
+#[test]
+fn test_observed_bidirectional() {
+    let mut bytes = control_frame(4, &[b"test-content-type"]);
+    bytes.extend_from_slice(&SAMPLE);
+    let reader = reader::reader(Duplex::new(&bytes));
+    assert!(!reader.observed_bidirectional());
+    let reader = reader.accept().unwrap().start().unwrap();
+    assert!(reader.observed_bidirectional());
+
+    let reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    assert!(!reader.observed_bidirectional());
+}
+
+#[test]
+fn test_follow() {
+    let path = std::env::temp_dir().join(format!("fstrm-follow-{}", std::process::id()));
+    let mut file = File::create(&path).unwrap();
+    let follower = File::open(&path).unwrap();
+    let writer = thread::spawn(move || {
+        for chunk in [&SAMPLE[..20], &SAMPLE[20..45], &SAMPLE[45..]] {
+            file.write_all(chunk).unwrap();
+            file.flush().unwrap();
+            thread::sleep(Duration::from_millis(30));
+        }
+    });
+
+    let follower = Follow::new(follower).interval(Duration::from_millis(5));
+    let mut reader = reader::reader(follower).start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().unwrap().is_none());
+    writer.join().unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_cancel_token() {
+    let token = CancelToken::new();
+    let reader = reader::reader(&SAMPLE[..]).cancel_token(token.clone());
+    let mut reader = reader.start().unwrap();
+    token.cancel();
+    let err = reader.read_frame().err().unwrap();
+    assert!(cancel::is_cancelled(&err));
+
+    let token = CancelToken::new();
+    let follower = Follow::new(&SAMPLE[..45])
+        .interval(Duration::from_millis(5))
+        .cancel_token(token.clone());
+    let mut reader = reader::reader(follower).start().unwrap();
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(30));
+        token.cancel();
+    });
+    let err = reader.read_string().unwrap_err();
+    assert!(cancel::is_cancelled(&err));
+    canceller.join().unwrap();
+}
+
+#[test]
+fn test_inner_error_unwrapped() {
+    // cancelled while waiting for the next frame header
+    let token = CancelToken::new();
+    let follower = Follow::new(&SAMPLE[..53])
+        .interval(Duration::from_millis(5))
+        .cancel_token(token.clone());
+    let mut reader = reader::reader(follower).start().unwrap();
+    assert_eq!(
+        reader.read_string().unwrap().as_deref(),
+        Some("test-content")
+    );
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(30));
+        token.cancel();
+    });
+    let err = reader.read_frame().err().unwrap();
+    assert!(cancel::is_cancelled(&err));
+    assert_eq!(reader::error_offset(&err), None);
+    canceller.join().unwrap();
+
+    struct Reset<'a>(&'a [u8]);
+    impl Read for Reset<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::Error::from_raw_os_error(104)),
+                n => Ok(n),
+            }
+        }
+    }
+    let mut reader = reader::reader(Reset(&SAMPLE[..39])).start().unwrap();
+    let err = reader.read_frame().err().unwrap();
+    assert_eq!(err.raw_os_error(), Some(104));
+    assert_eq!(reader::error_offset(&err), None);
+}
+
+#[test]
+fn test_write_probe() {
+    let mut buf = vec![];
+    writer::write_probe(&mut buf, Some("test-content-type")).unwrap();
+    assert_eq!(buf, SAMPLE[..37]);
+    let probe = crate::probe(&mut &buf[..]).unwrap();
+    assert_eq!(probe.direction, Some(Direction::UniDirectional));
+    assert_eq!(probe.content_types, ["test-content-type"]);
+
+    let mut buf = vec![];
+    writer::write_probe(&mut buf, None).unwrap();
+    assert!(crate::probe(&mut &buf[..]).unwrap().is_valid());
+}
+
+#[test]
+fn test_interleaved_control_frame() {
+    let mut bytes = SAMPLE[..53].to_vec();
+    bytes.extend_from_slice(&control_frame(1, &[b"a"]));
+    bytes.extend_from_slice(&[0, 0, 0, 1, b'x']);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let frame = reader.read_next_frame().unwrap();
+    assert!(
+        matches!(&frame, Some(Frame::Data(d)) if d == b"test-content"),
+        "{:?}",
+        frame
+    );
+    let frame = reader.read_next_frame().unwrap();
+    assert!(
+        matches!(&frame, Some(Frame::Control(c))
+            if c.control_type() == ControlType::Accept && c.content_types() == ["a"]),
+        "{:?}",
+        frame
+    );
+    let frame = reader.read_next_frame().unwrap();
+    assert!(
+        matches!(&frame, Some(Frame::Data(d)) if d == b"x"),
+        "{:?}",
+        frame
+    );
+    let frame = reader.read_next_frame().unwrap();
+    assert!(
+        matches!(&frame, Some(Frame::Control(c)) if c.control_type() == ControlType::Stop),
+        "{:?}",
+        frame
+    );
+    assert!(reader.read_next_frame().unwrap().is_none());
+
+    // read_frame() fails on the ACCEPT but stays in sync
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().is_err());
+    assert_eq!(reader.read_string().unwrap().unwrap(), "x");
+    assert!(reader.read_string().unwrap().is_none());
+}
+
+#[test]
+fn test_read_frame_into() {
+    let mut bytes = SAMPLE[..53].to_vec();
+    bytes.extend_from_slice(&[0, 0, 0, 2, b'o', b'k']);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(reader.read_frame_into(&mut buf).unwrap(), Some(12));
+    assert_eq!(buf, b"test-content");
+    let capacity = buf.capacity();
+    assert_eq!(reader.read_frame_into(&mut buf).unwrap(), Some(2));
+    assert_eq!(buf, b"ok");
+    assert_eq!(buf.capacity(), capacity);
+    assert_eq!(reader.read_frame_into(&mut buf).unwrap(), None);
+}
+
+#[test]
+fn test_read_only_content_type() {
+    let mut bytes = SAMPLE.to_vec();
+    bytes.extend_from_slice(&control_frame(2, &[b"other"]));
+    bytes.extend_from_slice(&[0, 0, 0, 1, b'x']);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+    bytes.extend_from_slice(&SAMPLE);
+
+    let reader = reader::reader(&bytes[..])
+        .multi_stream(true)
+        .read_only_content_type("test-content-type".into());
+    let mut reader = reader.start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().unwrap().is_none());
+
+    let reader = reader::reader(&bytes[..])
+        .multi_stream(true)
+        .read_only_content_type("other".into());
+    let mut reader = reader.start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "x");
+    assert!(reader.read_string().unwrap().is_none());
+
+    let reader = reader::reader(&bytes[..]).multi_stream(true);
+    let mut reader = reader.start().unwrap();
+    let mut frames = vec![];
+    while let Some(frame) = reader.read_string().unwrap() {
+        frames.push(frame);
+    }
+    assert_eq!(frames, ["test-content", "x", "test-content"]);
+}
+
+#[test]
+fn test_truncated_data_frame() {
+    let mut bytes = SAMPLE[..37].to_vec();
+    bytes.extend_from_slice(&[0, 0, 0, 12, b'p', b'a', b'r']);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let mut buf = b"stale".to_vec();
+    let err = reader.read_frame_into(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(buf, b"par");
+}
+
+#[test]
+fn test_field_exceeds_frame() {
+    let mut bytes = control_frame(2, &[b"abc"]);
+    bytes[19] = 5; // field length 5 with 3 bytes left in frame
+    let err = reader::reader(&bytes[..]).start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.to_string(),
+        "control field exceeds frame (5 bytes, 3 remaining) (at offset 12)"
+    );
+}
+
+#[test]
+fn test_stop_with_stats() {
+    let mut plain = vec![];
+    writer::write_stop(&mut plain, None).unwrap();
+    assert_eq!(plain, SAMPLE[53..]);
+
+    let stats = StreamStats {
+        frames: 1,
+        bytes: 12,
+    };
+    let mut bytes = SAMPLE[..53].to_vec();
+    writer::write_stop(&mut bytes, Some(stats)).unwrap();
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    assert!(reader.stop_stats().is_none());
+    while reader.read_frame().unwrap().is_some() {}
+    assert_eq!(reader.stop_stats(), Some(stats));
+
+    let mut reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    while reader.read_frame().unwrap().is_some() {}
+    assert!(reader.stop_stats().is_none());
+}
+
+#[test]
+fn test_decode_files() {
+    let dir = std::env::temp_dir().join(format!("fstrm-batch-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut paths = vec![];
+    for i in 0..5 {
+        let path = dir.join(format!("{}.fstrm", i));
+        fs::write(&path, &SAMPLE[..]).unwrap();
+        paths.push(path);
+    }
+    let broken = dir.join("broken.fstrm");
+    fs::write(&broken, &SAMPLE[..40]).unwrap();
+    paths.push(broken.clone());
+    let unstopped = dir.join("unstopped.fstrm");
+    fs::write(&unstopped, &SAMPLE[..53]).unwrap();
+    paths.push(unstopped.clone());
+
+    let summaries = Mutex::new(vec![]);
+    let errors = batch::decode_files(&paths, 3, |summary| summaries.lock().unwrap().push(summary));
+    fs::remove_dir_all(&dir).unwrap();
+
+    let summaries = summaries.into_inner().unwrap();
+    assert_eq!(summaries.len(), 5);
+    assert!(summaries.iter().all(|s| s.frames == 1 && s.bytes == 12));
+    assert!(summaries[0].content_types.contains("test-content-type"));
+    assert_eq!(errors.len(), 2);
+    for path in [broken, unstopped] {
+        let (_, err) = errors.iter().find(|(p, _)| *p == path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_positional_writer() {
+    use writer::{positional::Regions, uni_directional::Writer};
+
+    let path = std::env::temp_dir().join(format!("fstrm-regions-{}", std::process::id()));
+    let payloads: [&[&[u8]]; 2] = [&[b"test-content"], &[b"a", b"bc"]];
+    let sizes: Vec<u64> = payloads
+        .iter()
+        .map(|p| {
+            let lens: Vec<_> = p.iter().map(|p| p.len()).collect();
+            crate::planned_stream_size(Some("test-content-type"), &lens).unwrap()
+        })
+        .collect();
+    let regions = Regions::new(File::create(&path).unwrap(), sizes.iter().sum()).unwrap();
+    let writers: Vec<_> = sizes.iter().map(|&n| regions.region(n).unwrap()).collect();
+    assert!(regions.region(1).is_err());
+    let ranges: Vec<_> = writers.iter().map(|w| w.range()).collect();
+
+    // the second region is written first
+    let handles: Vec<_> = writers
+        .into_iter()
+        .zip(payloads.iter())
+        .rev()
+        .map(|(region, payloads)| {
+            let payloads: Vec<Vec<u8>> = payloads.iter().map(|p| p.to_vec()).collect();
+            thread::spawn(move || {
+                let mut w = Writer::new(region, "test-content-type").unwrap();
+                for payload in payloads {
+                    w.write_data(&payload).unwrap();
+                }
+                let region = w.finish().unwrap();
+                assert_eq!(region.written(), region.range().end - region.range().start);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        &bytes[ranges[0].start as usize..ranges[0].end as usize],
+        SAMPLE
+    );
+    let region = &bytes[ranges[1].start as usize..ranges[1].end as usize];
+    let mut reader = reader::reader(region).start().unwrap();
+    assert_eq!(reader.read_string().unwrap().as_deref(), Some("a"));
+    assert_eq!(reader.read_string().unwrap().as_deref(), Some("bc"));
+    assert!(reader.read_string().unwrap().is_none());
+    assert!(reader.is_stopped());
+}
+
+#[test]
+fn test_uni_directional_writer() {
+    let mut w = writer::uni_directional::Writer::new(vec![], "test-content-type").unwrap();
+    w.write_data(b"test-content").unwrap();
+    let err = w.write_data(b"").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(w.finish().unwrap(), SAMPLE);
+
+    let mut bytes = vec![];
+    {
+        let mut w = writer::uni_directional::Writer::new(&mut bytes, "test-content-type").unwrap();
+        w.write_data(b"test-content").unwrap();
+    }
+    assert_eq!(bytes, SAMPLE);
+}
+
+#[test]
+fn test_strict_terminal() {
+    let mut bytes = SAMPLE.to_vec();
+    bytes.extend_from_slice(&SAMPLE[37..53]);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    assert!(reader.read_string().unwrap().is_some());
+    assert!(reader.read_string().unwrap().is_none());
+
+    let reader = reader::reader(&bytes[..]).strict_terminal(true);
+    let mut reader = reader.start().unwrap();
+    assert!(reader.read_string().unwrap().is_some());
+    let err = reader.read_string().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let reader = reader::reader(&SAMPLE[..]).strict_terminal(true);
+    let mut reader = reader.start().unwrap();
+    assert!(reader.read_string().unwrap().is_some());
+    assert!(reader.read_string().unwrap().is_none());
+}
+
+#[test]
+fn test_bi_directional_client() {
+    let mut input = control_frame(1, &[b"other", b"test-content-type"]);
+    input.extend_from_slice(&control_frame(5, &[]));
+    let offered = vec!["test-content-type".to_string(), "other".to_string()];
+    let mut client = writer::bi_directional::Client::connect(Duplex::new(&input), offered).unwrap();
+    assert_eq!(client.content_type(), Some("test-content-type"));
+    client.write_data(b"test-content").unwrap();
+    let stream = client.finish().unwrap();
+
+    let mut expected = control_frame(4, &[b"test-content-type", b"other"]);
+    expected.extend_from_slice(&SAMPLE);
+    assert_eq!(stream.output, expected);
+
+    let input = control_frame(1, &[b"other"]);
+    let offered = vec!["test-content-type".to_string()];
+    let err = writer::bi_directional::Client::connect(Duplex::new(&input), offered)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_queue_writes() {
+    // while `busy` bytes are written, takes at most 3 bytes a call and
+    // blocks every other call
+    struct SlowSocket {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        busy: std::ops::Range<usize>,
+        block: bool,
+    }
+    impl Read for SlowSocket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+    impl Write for SlowSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.busy.contains(&self.output.len()) {
+                return self.output.write(buf);
+            }
+            self.block = !self.block;
+            if self.block {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(3);
+            self.output.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut input = control_frame(4, &[b"test-content-type"]);
+    input.extend_from_slice(&SAMPLE);
+    let mut expected = control_frame(1, &[b"test-content-type"]);
+    let socket = SlowSocket {
+        input: io::Cursor::new(input),
+        output: vec![],
+        busy: expected.len()..expected.len() + 32,
+        block: false,
+    };
+    let reader = reader::reader(socket).queue_writes(64);
+    let mut reader = reader.accept().unwrap().start().unwrap();
+    reader.write_data_checked(b"test-content").unwrap();
+    reader.write_data_checked(b"test-content").unwrap();
+    assert!(reader.queued_writes() > 0);
+    let err = reader.write_data_checked(&[b'x'; 64]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    let mut rounds = 0;
+    while !reader.flush_writes().unwrap() {
+        rounds += 1;
+        assert!(rounds < 100);
+    }
+    assert_eq!(reader.queued_writes(), 0);
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().unwrap().is_none());
+
+    expected.extend_from_slice(&SAMPLE[37..53]);
+    expected.extend_from_slice(&SAMPLE[37..53]);
+    expected.extend_from_slice(&control_frame(5, &[]));
+    assert_eq!(reader.finish().unwrap().output, expected);
+}
+
+#[test]
+fn test_handshake_replay() {
+    use writer::bi_directional::Client;
+
+    let mut input = control_frame(1, &[b"other", b"test-content-type"]);
+    input.extend_from_slice(&control_frame(5, &[]));
+    let offered = vec!["test-content-type".to_string(), "other".to_string()];
+    let client = Client::connect(Duplex::new(&input), offered).unwrap();
+    let record = client.handshake().clone();
+    let first = client.finish().unwrap().output;
+
+    // same READY and START over a new connection
+    let mut input = control_frame(1, &[b"test-content-type"]);
+    input.extend_from_slice(&control_frame(5, &[]));
+    let client = Client::reconnect(Duplex::new(&input), &record).unwrap();
+    assert_eq!(client.content_type(), Some("test-content-type"));
+    assert_eq!(client.handshake(), &record);
+    assert_eq!(client.finish().unwrap().output, first);
+
+    let input = control_frame(1, &[b"other"]);
+    let mut stream = Duplex::new(&input);
+    let err = record.replay(&mut stream).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_cursor_from_frames() {
+    let frames = vec![
+        Frame::Control(ControlFrame::new(
+            ControlType::Start,
+            vec!["test-content-type".to_string()],
+        )),
+        Frame::data("test-content"),
+        Frame::Control(ControlFrame::new(ControlType::Stop, None)),
+    ];
+    let cursor = crate::testing::cursor_from_frames(frames);
+    assert_eq!(cursor.get_ref()[..], SAMPLE);
+
+    let mut reader = reader::reader(cursor).start().unwrap();
+    assert_eq!(reader.content_types().len(), 1);
+    assert!(reader.content_types().contains("test-content-type"));
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().unwrap().is_none());
+    assert!(reader.is_stopped());
+}
+
+#[test]
+fn test_frame_from_bytes() {
+    let frames = [
+        Frame::from(b"test-content".as_slice()),
+        Frame::from(b"test-content".to_vec()),
+        Frame::from(*b"test-content"),
+        Frame::data("test-content"),
+    ];
+    for frame in frames.iter() {
+        let mut buf = vec![];
+        writer::write_frame(&mut buf, frame).unwrap();
+        assert_eq!(buf, SAMPLE[37..53]);
+    }
+}
+
+#[test]
+fn test_configurable_control_limits() {
+    let long = vec![b'a'; 300];
+    let bytes = control_frame(2, &[&long]);
+    assert!(reader::reader(&bytes[..]).start().is_err());
+    let reader = reader::reader(&bytes[..]).max_content_type_length(300);
+    let reader = reader.start().ok().unwrap();
+    assert!(reader.content_types().contains(&"a".repeat(300)));
+
+    let types: Vec<Vec<u8>> = (0..40)
+        .map(|i| format!("type-{:02}", i).into_bytes())
+        .collect();
+    let types: Vec<&[u8]> = types.iter().map(Vec::as_slice).collect();
+    let bytes = control_frame(2, &types);
+    assert!(bytes.len() > 512);
+    assert!(reader::reader(&bytes[..]).start().is_err());
+    let reader = reader::reader(&bytes[..]).max_control_frame_length(1024);
+    assert_eq!(reader.start().ok().unwrap().content_types().len(), 40);
+}
+
+/// Count calls to `read` of the inner reader.
+struct CountingReader<R> {
+    inner: R,
+    reads: Arc<Mutex<usize>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        *self.reads.lock().unwrap() += 1;
+        self.inner.read(buf)
+    }
+}
+
+#[test]
+fn test_read_chunk_size() {
+    let mut bytes = SAMPLE[..37].to_vec();
+    bytes.extend_from_slice(&40_000u32.to_be_bytes());
+    bytes.extend_from_slice(&[7; 40_000]);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let reads_for = |chunk| {
+        let reads = Arc::new(Mutex::new(0));
+        let inner = CountingReader {
+            inner: &bytes[..],
+            reads: reads.clone(),
+        };
+        let mut reader = reader::reader(inner)
+            .read_chunk_size(chunk)
+            .start()
+            .unwrap();
+        let before = *reads.lock().unwrap();
+        let payload = reader.read_payload().unwrap().unwrap();
+        assert!(matches!(payload, Payload::Complete(ref v) if v.len() == 40_000));
+        let after = *reads.lock().unwrap();
+        after - before // one more for the frame header
+    };
+    assert_eq!(reads_for(1_000), 40 + 1);
+    assert_eq!(reads_for(10_000), 4 + 1);
+    assert_eq!(reads_for(usize::MAX), 1 + 1);
+}
+
+#[test]
+fn test_buffered_reader() {
+    let reader = reader::buffered_reader(&SAMPLE[..], 64 * 1024);
+    let mut reader = reader.start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert_eq!(reader.into_inner().capacity(), 64 * 1024);
+}
+
+#[test]
+fn test_control_frame_semantically_eq() {
+    let types = |types: &[&str]| types.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+    let a = ControlFrame::new(ControlType::Accept, types(&["a", "b"]));
+    let b = ControlFrame::new(ControlType::Accept, types(&["b", "a"]));
+    assert!(a.semantically_eq(&b));
+    assert_ne!(a.encode_body().unwrap(), b.encode_body().unwrap());
+
+    let ready = ControlFrame::new(ControlType::Ready, types(&["a", "b"]));
+    assert!(!a.semantically_eq(&ready));
+    let fewer = ControlFrame::new(ControlType::Accept, types(&["a"]));
+    assert!(!a.semantically_eq(&fewer));
+}
+
+#[test]
+fn test_frames() {
+    let mut bytes = SAMPLE[..53].to_vec();
+    bytes.extend_from_slice(&control_frame(5, &[]));
+    bytes.extend_from_slice(&SAMPLE[37..]);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    assert!(reader.read_string().unwrap().is_some());
+    let err = reader.read_string().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "unexpected control frame Finish");
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let frames: Vec<_> = reader.frames().map(Result::unwrap).collect();
+    assert_eq!(frames.len(), 4);
+    assert!(matches!(&frames[0], Frame::Data(data) if data == b"test-content"));
+    assert!(matches!(&frames[1], Frame::Control(c) if c.control_type() == ControlType::Finish));
+    assert!(matches!(&frames[2], Frame::Data(_)));
+    assert!(matches!(&frames[3], Frame::Control(c) if c.control_type() == ControlType::Stop));
+}
+
+#[test]
+fn test_write_substream() {
+    let mut bytes = vec![];
+    writer::write_substream(&mut bytes, Some("test-content-type"), ["test-content"]).unwrap();
+    assert_eq!(bytes, SAMPLE);
+
+    writer::write_substream(
+        &mut bytes,
+        Some("other"),
+        vec![b"a".to_vec(), b"bc".to_vec()],
+    )
+    .unwrap();
+    let reader = reader::reader(&bytes[..]).multi_stream(true);
+    let mut reader = reader.start().unwrap();
+    assert!(reader.content_types().contains("test-content-type"));
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert_eq!(reader.read_string().unwrap().unwrap(), "a");
+    assert!(reader.content_types().contains("other"));
+    assert_eq!(reader.read_string().unwrap().unwrap(), "bc");
+    assert!(reader.read_string().unwrap().is_none());
+}
+
+#[test]
+fn test_eof_before_stop() {
+    let mut reader = reader::reader(&SAMPLE[..53]).start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().unwrap().is_none());
+    assert!(!reader.is_stopped());
+
+    let mut reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    while reader.read_string().unwrap().is_some() {}
+    assert!(reader.is_stopped());
+
+    for len in [39, 45, 55] {
+        let mut reader = reader::reader(&SAMPLE[..len]).start().unwrap();
+        let err = loop {
+            match reader.read_string() {
+                Ok(Some(_)) => continue,
+                Ok(None) => panic!("EOF at {} taken as clean end", len),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
+
+#[test]
+fn test_control_frame_display() {
+    let mut reader = reader::reader(&SAMPLE[..]);
+    let frame = reader.read_control_frame().unwrap();
+    assert_eq!(
+        frame.to_string(),
+        r#"FSTRM_CONTROL_START, FSTRM_CONTROL_FIELD_CONTENT_TYPE (17 bytes) "test-content-type""#
+    );
+    assert!(format!("{:?}", frame).contains("ContentType(\"test-content-type\")"));
+    let stop = ControlFrame::new(ControlType::Stop, None);
+    assert_eq!(stop.to_string(), "FSTRM_CONTROL_STOP");
+}
+
+#[test]
+fn test_little_endian() {
+    let mut bytes = vec![0, 0, 0, 0, 13, 0, 0, 0]; // READY, length 13 LE
+    bytes.extend_from_slice(&control_frame(4, &[b"t"])[8..]);
+    bytes.extend_from_slice(&[0, 0, 0, 0, 13, 0, 0, 0]); // START
+    bytes.extend_from_slice(&control_frame(2, &[b"t"])[8..]);
+    bytes.extend_from_slice(&[2, 0, 0, 0, b'h', b'i']);
+    bytes.extend_from_slice(&[0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 3]); // STOP
+
+    let reader = reader::reader(Duplex::new(&bytes)).little_endian(true);
+    let mut reader = reader.accept().unwrap().start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "hi");
+    assert!(reader.read_string().unwrap().is_none());
+    let output = reader.finish().unwrap().output;
+    let mut expected = vec![0, 0, 0, 0, 13, 0, 0, 0];
+    expected.extend_from_slice(&control_frame(1, &[b"t"])[8..]);
+    expected.extend_from_slice(&[0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 5]);
+    assert_eq!(output, expected);
+
+    assert!(reader::reader(Duplex::new(&bytes)).accept().is_err());
+    let mut reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+
+    // the buffered fast path knows big-endian u32 only
+    let reader = reader::reader(io::BufReader::new(&bytes[8 + 13..])).little_endian(true);
+    let mut reader = reader.start().unwrap();
+    assert!(reader.try_read_buffered_frame().unwrap().is_none());
+    assert_eq!(reader.read_string().unwrap().unwrap(), "hi");
+}
+
+#[test]
+fn test_length_u32() {
+    assert_eq!(writer::length_u32(12, "data frame").unwrap(), 12);
+    assert_eq!(
+        writer::length_u32(u32::MAX as usize, "data frame").unwrap(),
+        u32::MAX
+    );
+    if let Some(len) = (u32::MAX as usize).checked_add(1) {
+        let err = writer::length_u32(len, "data frame").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "data frame too large");
+    }
+}
+
+#[test]
+fn test_error_offset() {
+    let mut bytes = SAMPLE.to_vec();
+    bytes[59] = 0x10; // STOP at 53 declares 4096 bytes
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    assert!(reader.read_string().unwrap().is_some());
+    let err = reader.read_string().err().unwrap();
+    assert_eq!(reader::error_offset(&err), Some(53));
+    assert_eq!(err.to_string(), "control frame too large (at offset 53)");
+
+    let mut bytes = SAMPLE.to_vec();
+    bytes[20] = 0xff; // invalid utf-8 in content type
+    let err = reader::reader(&bytes[..]).start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(reader::error_offset(&err), Some(12));
+
+    let err = reader::reader(&SAMPLE[..20]).start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(reader::error_offset(&err), Some(20));
+}
+
+#[test]
+fn test_write_data_checked() {
+    let mut input = control_frame(4, &[b"test-content-type"]);
+    input.extend_from_slice(&SAMPLE);
+    let reader = reader::reader(Duplex::new(&input));
+    let mut reader = reader.accept().unwrap().start().unwrap();
+    reader.write_data_checked(b"reply").unwrap();
+    let err = reader.write_data_checked(b"").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let accept_len = control_frame(1, &[b"test-content-type"]).len();
+    let output = reader.into_inner().output;
+    assert_eq!(
+        output[accept_len..],
+        [0, 0, 0, 5, b'r', b'e', b'p', b'l', b'y']
+    );
+
+    let mut reader = reader::reader(Duplex::new(&SAMPLE)).start().unwrap();
+    let err = reader.write_data_checked(b"reply").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let mut input = control_frame(4, &[]);
+    input.extend_from_slice(&control_frame(2, &[]));
+    let reader = reader::reader(Duplex::new(&input));
+    let mut reader = reader.accept().unwrap().start().unwrap();
+    assert!(reader.write_data_checked(b"reply").is_err());
+}
+
+#[test]
+fn test_start_with_multiple_content_types() {
+    let mut bytes = control_frame(2, &[b"a", b"test-content-type"]);
+    bytes.extend_from_slice(&SAMPLE[37..]);
+
+    let reader = reader::reader(&bytes[..]);
+    let reader = reader.start().ok().unwrap();
+    assert_eq!(reader.content_types().len(), 2);
+
+    let reader = reader::reader_for_content_types(&bytes[..], vec!["test-content-type".into()]);
+    let mut reader = reader.start().ok().unwrap();
+    assert_eq!(reader.content_types().len(), 1);
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+}
+
+#[test]
+fn test_frame_decode() {
+    let mut bytes = &SAMPLE[..];
+    let start = Frame::decode(&mut bytes).unwrap();
+    assert!(
+        matches!(&start, Frame::Control(c) if c.content_types() == ["test-content-type"]),
+        "{:?}",
+        start
+    );
+    assert!(matches!(Frame::decode(&mut bytes).unwrap(), Frame::Data(d) if d == b"test-content"));
+    assert!(matches!(
+        Frame::decode(&mut bytes).unwrap(),
+        Frame::Control(_)
+    ));
+    assert!(bytes.is_empty());
+    assert!(Frame::decode(&mut bytes).is_err());
+}
+
+#[test]
+fn test_frame_round_trip() {
+    // xorshift, so failures can be replayed
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move |n: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % n
+    };
+    let mut frames = vec![];
+    for _ in 0..500 {
+        let frame = if next(3) == 0 {
+            let typ = ControlType::from(1 + next(5) as u32);
+            let types = (0..next(3)).map(|_| "x".repeat(1 + next(8) as usize));
+            Frame::Control(ControlFrame::new(typ, types.collect::<Vec<_>>()))
+        } else {
+            // lengths such as 256 or 512 put zero bytes in the prefix
+            let len = 1 + next(600) as usize;
+            Frame::Data((0..len).map(|_| next(3) as u8).collect())
+        };
+        frames.push(frame);
+    }
+    let mut buf = vec![];
+    for frame in &frames {
+        writer::write_frame(&mut buf, frame).unwrap();
+    }
+    assert!(writer::write_frame(&mut buf, &Frame::Data(vec![])).is_err());
+
+    let mut bytes = &buf[..];
+    for expected in &frames {
+        let decoded = Frame::decode(&mut bytes).unwrap();
+        match (expected, &decoded) {
+            (Frame::Data(a), Frame::Data(b)) => assert_eq!(a, b),
+            (Frame::Control(a), Frame::Control(b)) => {
+                assert_eq!(a.control_type(), b.control_type());
+                assert_eq!(a.content_types(), b.content_types());
+            }
+            _ => panic!("{:?} decoded as {:?}", expected, decoded),
+        }
+    }
+    assert!(bytes.is_empty());
+}
+
+#[test]
+fn test_throttled_relay() {
+    let mut bytes = SAMPLE[..53].to_vec();
+    for _ in 0..4 {
+        bytes.extend_from_slice(&SAMPLE[37..53]);
+    }
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let mut output = vec![];
+    let begin = Instant::now();
+    let count = crate::throttled_relay(&bytes[..], &mut output, 50).unwrap();
+    let elapsed = begin.elapsed();
+    assert_eq!(count, 5);
+    assert_eq!(output, bytes);
+    // first frame goes at once, the other 4 at 20ms intervals
+    assert!(elapsed >= Duration::from_millis(80));
+    assert!(elapsed < Duration::from_millis(500));
+}
+
+#[test]
+fn test_reader_chained() {
+    let chunks = vec![SAMPLE[..3].to_vec(), vec![], SAMPLE[3..40].to_vec()];
+    let reader = reader::reader_chained(chunks, &SAMPLE[40..]);
+    let mut reader = reader.start().unwrap();
+    assert!(reader.content_types().contains("test-content-type"));
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+    assert!(reader.read_string().unwrap().is_none());
+}
+
+#[test]
+fn test_is_bidirectional() {
+    let reader = reader::reader(&SAMPLE[..]).start().unwrap();
+    assert!(!reader.is_bidirectional());
+    assert_eq!(reader.direction(), Direction::UniDirectional);
+
+    let mut input = control_frame(4, &[b"test-content-type"]);
+    input.extend_from_slice(&SAMPLE);
+    let reader = reader::reader(Duplex::new(&input)).accept().unwrap();
+    assert!(reader.is_bidirectional());
+    let reader = reader.start().unwrap();
+    assert_eq!(reader.direction(), Direction::BiDirectional);
+}
+
+#[test]
+fn test_owned_reader() {
+    // Content types are copied in, so nothing borrowed outlives the helper
+    fn make_reader(
+        types: &[&str],
+    ) -> reader::FstrmReader<io::Cursor<Vec<u8>>, reader::states::Ready> {
+        let types = types.iter().map(|t| t.to_string());
+        reader::reader_for_content_types(io::Cursor::new(SAMPLE.to_vec()), types)
+    }
+    fn assert_static<T: 'static>(_: &T) {}
+
+    let reader = make_reader(&["test-content-type"]);
+    assert_static(&reader);
+    let mut reader = reader.start().ok().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+}
+
+#[test]
+fn test_read_frame_to_writer() {
+    let mut bytes = SAMPLE[..37].to_vec();
+    bytes.extend_from_slice(&100_000u32.to_be_bytes());
+    bytes.extend((0..100_000u32).map(|i| i as u8));
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let mut reader = reader::reader(&bytes[..]).start().unwrap();
+    let mut out = vec![];
+    assert_eq!(
+        reader.read_frame_to_writer(&mut out).unwrap(),
+        Some(100_000)
+    );
+    assert_eq!(out, bytes[41..100_041]);
+    assert_eq!(reader.read_frame_to_writer(&mut out).unwrap(), None);
+}
+
+#[test]
+fn test_into_frames() {
+    fn frames(bytes: &'static [u8]) -> reader::IntoFrames<&'static [u8]> {
+        reader::reader(bytes).start().unwrap().into_frames()
+    }
+    let kinds: Vec<_> = frames(&SAMPLE)
+        .map(|frame| match frame.unwrap() {
+            Frame::Data(_) => None,
+            Frame::Control(frame) => Some(frame.control_type()),
+        })
+        .collect();
+    assert_eq!(kinds, [None, Some(ControlType::Stop)]);
+}
+
+#[test]
+fn test_content_type_preference() {
+    let mut input = control_frame(4, &[b"a", b"b", b"c"]);
+    input.extend_from_slice(&control_frame(2, &[b"a", b"b"]));
+    let server = |types: &[&str]| {
+        let types = types.iter().map(|t| t.to_string());
+        reader::reader_for_content_types(Duplex::new(&input), types)
+    };
+
+    let reader = server(&["b", "a"]).accept().ok().unwrap();
+    let reader = reader.start().ok().unwrap();
+    assert_eq!(reader.content_type(), Some("b"));
+    let accept_len = control_frame(1, &[b"b", b"a"]).len();
+    assert_eq!(
+        reader.into_inner().output[..accept_len],
+        control_frame(1, &[b"b", b"a"])[..]
+    );
+
+    let reader = server(&["a", "b"]).accept().ok().unwrap();
+    assert_eq!(reader.start().ok().unwrap().content_type(), Some("a"));
+
+    let reader = reader::reader(Duplex::new(&input)).accept().unwrap();
+    let reader = reader.start().unwrap();
+    assert_eq!(reader.content_type(), Some("a"));
+    let output = reader.into_inner().output;
+    assert_eq!(output, control_frame(1, &[b"a", b"b", b"c"]));
+}
+
+/// Keep log messages of all tests, trace level included.
+struct TestLogger;
+
+static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static LOGGER: TestLogger = TestLogger;
+
+impl log::Log for TestLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        LOGS.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+fn capture_logs() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+#[test]
+fn test_trace_bytes() {
+    capture_logs();
+    let mut bytes = SAMPLE[..37].to_vec();
+    bytes.extend_from_slice(&[0, 0, 0, 6, 0xf5, 0x7a, 0xce, 0xb1, 0xe5, 0x01]);
+    bytes.extend_from_slice(&SAMPLE[53..]);
+
+    let mut reader = reader::reader(&bytes[..]).trace_bytes(4).start().unwrap();
+    assert!(reader.read_payload().unwrap().is_some());
+    let logs = LOGS.lock().unwrap();
+    assert!(logs
+        .iter()
+        .any(|l| l == "data frame bytes: f5 7a ce b1 ..."));
+    assert!(logs
+        .iter()
+        .any(|l| l == "control frame bytes: 00 00 00 01 ..."));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_full_duplex() {
+    use std::os::unix::net::UnixStream;
+    let (local, remote) = UnixStream::pair().unwrap();
+
+    let initiator = thread::spawn(move || {
+        let types = vec!["test-content-type".to_string()];
+        let mut client = writer::bi_directional::Client::connect(remote, types).unwrap();
+        client.write_data(b"request").unwrap();
+        let reply = client.read_frame().unwrap();
+        client.finish().unwrap();
+        reply
+    });
+
+    let reader = reader::reader(local).accept().unwrap();
+    let mut reader = reader.start().unwrap();
+    assert_eq!(reader.read_string().unwrap().unwrap(), "request");
+    reader.write_frame(&Frame::data("response")).unwrap();
+    let stop = Frame::Control(ControlFrame::new(ControlType::Stop, None));
+    assert!(reader.write_frame(&stop).is_err());
+    assert!(reader.read_string().unwrap().is_none());
+    reader.finish().unwrap();
+
+    let reply = initiator.join().unwrap();
+    assert!(matches!(reply, Frame::Data(data) if data == b"response"));
+}
+
+#[test]
+fn test_planned_stream_size() {
+    let size = crate::planned_stream_size(Some("test-content-type"), &[12]).unwrap();
+    assert_eq!(size, SAMPLE.len() as u64);
+
+    let payloads = [vec![1; 3], vec![2; 1000], vec![3; 1]];
+    let lens: Vec<_> = payloads.iter().map(Vec::len).collect();
+    for typ in [None, Some("a")] {
+        let mut bytes = vec![];
+        writer::write_substream(&mut bytes, typ, &payloads).unwrap();
+        let size = crate::planned_stream_size(typ, &lens).unwrap();
+        assert_eq!(size, bytes.len() as u64);
+    }
+    assert!(crate::planned_stream_size(None, &[0]).is_err());
+}
+
+#[test]
+fn test_relay_strip_content_type() {
+    let mut output = vec![];
+    let relay = crate::Relay::new().strip_content_type(true);
+    assert_eq!(relay.run(&SAMPLE[..], &mut output).unwrap(), 1);
+
+    let mut expected = control_frame(2, &[]);
+    expected.extend_from_slice(&SAMPLE[37..]);
+    assert_eq!(output, expected);
+    let reader = reader::reader(&output[..]).start().unwrap();
+    assert!(reader.content_types().is_empty());
+}
+
+#[test]
+fn test_relay_without_stop() {
+    let mut output = vec![];
+    let err = crate::Relay::new()
+        .run(&SAMPLE[..53], &mut output)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(output, SAMPLE[..53]);
+}
+
+#[test]
+fn test_discard_content_type() {
+    let types = vec!["test-content-type".to_string()];
+    let reader = reader::reader_for_content_types(&SAMPLE[..], types.clone());
+    let reader = reader.discard_content_type(true).capture_handshake(true);
+    let mut reader = reader.start().ok().unwrap();
+    assert!(reader.content_types().is_empty());
+    assert!(reader.content_type().is_none());
+    // never copied out of the frame body
+    assert!(reader.handshake()[0].content_types().is_empty());
+    assert_eq!(reader.read_string().unwrap().unwrap(), "test-content");
+
+    let reader = reader::reader_for_content_types(&SAMPLE[..], types.clone());
+    let reader = reader.capture_handshake(true).start().ok().unwrap();
+    assert_eq!(reader.handshake()[0].content_types(), ["test-content-type"]);
+
+    let bytes = control_frame(2, &[b"other"]);
+    let reader = reader::reader_for_content_types(&bytes[..], types);
+    assert!(reader.discard_content_type(true).start().is_err());
+
+    let reader = reader::reader(&SAMPLE[..])
+        .read_only_content_type("test-content-type".into())
+        .discard_content_type(true);
+    let err = reader.start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_start_outside_accepted() {
+    let mut input = control_frame(4, &[b"a"]);
+    input.extend_from_slice(&control_frame(2, &[b"b"]));
+    let reader = reader::reader(Duplex::new(&input)).accept().unwrap();
+    let err = reader.start().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let mut input = control_frame(4, &[b"a", b"b"]);
+    input.extend_from_slice(&control_frame(2, &[b"b", b"c"]));
+    let reader = reader::reader(Duplex::new(&input)).accept().unwrap();
+    let reader = reader.start().unwrap();
+    assert_eq!(reader.content_types().len(), 1);
+    assert_eq!(reader.content_type(), Some("b"));
+}
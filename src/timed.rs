@@ -0,0 +1,40 @@
+use crate::reader::{states, DataFrame, FstrmReader};
+use std::{
+    io::{Read, Result},
+    time::{Duration, Instant},
+};
+
+/// A diagnostic wrapper that reports the time gap between data frames.
+///
+/// Useful for spotting stalls and bursts from a live producer.
+pub struct TimedReader<R> {
+    reader: FstrmReader<R, states::Started>,
+    last: Instant,
+}
+
+impl<R> TimedReader<R> {
+    /// Wrap a started reader. The gap of the first frame is measured
+    /// from the moment this wrapper is created.
+    pub fn new(reader: FstrmReader<R, states::Started>) -> Self {
+        Self {
+            reader,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn into_inner(self) -> FstrmReader<R, states::Started> {
+        self.reader
+    }
+}
+
+impl<R: Read> TimedReader<R> {
+    /// Read the next data frame, along with the time elapsed since the
+    /// previous one was returned. Return None on STOP.
+    pub fn read_frame(&mut self) -> Result<Option<(Duration, DataFrame<'_, R>)>> {
+        let frame = self.reader.read_frame()?;
+        let now = Instant::now();
+        let gap = now.duration_since(self.last);
+        self.last = now;
+        Ok(frame.map(|frame| (gap, frame)))
+    }
+}
@@ -0,0 +1,103 @@
+use crate::reader::{ControlFrame, ControlType, Frame, StreamStats};
+use byteorder::{BigEndian, WriteBytesExt};
+use std::{
+    convert::TryInto,
+    io::{self, ErrorKind, Result, Write},
+};
+
+pub mod bi_directional;
+#[cfg(unix)]
+pub mod positional;
+pub mod uni_directional;
+
+/// Convert a length for the wire, failing instead of truncating it.
+pub(crate) fn length_u32(len: usize, what: &str) -> Result<u32> {
+    len.try_into()
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("{} too large", what)))
+}
+
+/// Write a complete control frame, header included.
+pub(crate) fn write_control_frame<W: Write>(w: &mut W, frame: &ControlFrame) -> Result<()> {
+    let body = frame.encode_body()?;
+    w.write_u32::<BigEndian>(0)?; // escape
+    w.write_u32::<BigEndian>(length_u32(body.len(), "control frame")?)?;
+    w.write_all(&body)
+}
+
+/// Write a data frame. An empty payload is refused since its zero length
+/// would be read as the escape of a control frame.
+pub(crate) fn write_data_frame<W: Write>(w: &mut W, data: &[u8]) -> Result<()> {
+    if data.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "data frame cannot be empty",
+        ));
+    }
+    w.write_u32::<BigEndian>(length_u32(data.len(), "data frame")?)?;
+    w.write_all(data)
+}
+
+/// Write a frame, data or control.
+pub fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> Result<()> {
+    match frame {
+        Frame::Data(data) => write_data_frame(w, data),
+        Frame::Control(frame) => write_control_frame(w, frame),
+    }
+}
+
+/// Write a whole sub-stream: START with `content_type`, a data frame for
+/// each of `payloads`, then STOP, and flush.
+pub fn write_substream<W, I, P>(w: &mut W, content_type: Option<&str>, payloads: I) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = P>,
+    P: AsRef<[u8]>,
+{
+    let start = ControlFrame::new(ControlType::Start, content_type.map(String::from));
+    write_control_frame(w, &start)?;
+    for payload in payloads {
+        write_data_frame(w, payload.as_ref())?;
+    }
+    write_stop(w, None)?;
+    w.flush()
+}
+
+/// Exact size of a uni-directional stream as `write_substream` would
+/// write it, with START for `content_type`, data frames of `payload_lens`
+/// and STOP. Fail on lengths those writers would refuse.
+pub fn planned_stream_size(content_type: Option<&str>, payload_lens: &[usize]) -> Result<u64> {
+    // escape, length and control type
+    let control = 12u64;
+    let mut size = control * 2;
+    if let Some(typ) = content_type {
+        size += 8 + u64::from(length_u32(typ.len(), "content type")?);
+    }
+    for &len in payload_lens {
+        if len == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "data frame cannot be empty",
+            ));
+        }
+        size += 4 + u64::from(length_u32(len, "data frame")?);
+    }
+    Ok(size)
+}
+
+/// Write just a START frame, the shortest prefix that identifies an
+/// fstrm stream, e.g. for a peer doing protocol detection on a shared
+/// port. `probe()` recognises it on the other side.
+pub fn write_probe<W: Write>(w: &mut W, content_type: Option<&str>) -> Result<()> {
+    let frame = ControlFrame::new(ControlType::Start, content_type.map(String::from));
+    write_control_frame(w, &frame)
+}
+
+/// Write STOP, with `stats` as a non-standard field if given. Readers
+/// of this crate expose them through `stop_stats()`.
+pub fn write_stop<W: Write>(w: &mut W, stats: Option<StreamStats>) -> Result<()> {
+    let frame = match stats {
+        Some(stats) => ControlFrame::stop_with_stats(stats),
+        None => ControlFrame::new(ControlType::Stop, None),
+    };
+    write_control_frame(w, &frame)
+}
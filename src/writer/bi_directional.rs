@@ -0,0 +1,135 @@
+use super::{write_control_frame, write_data_frame, write_stop};
+use crate::reader::{self, ControlFrame, ControlType, Frame};
+use std::io::{self, ErrorKind, Read, Result, Write};
+
+/// Initiator of a bi-directional stream, the sending side of a socket.
+///
+/// Handshake is READY, ACCEPT, START; `finish()` then sends STOP and
+/// waits for FINISH.
+pub struct Client<S> {
+    stream: S,
+    handshake: HandshakeRecord,
+}
+
+/// What a `Client` offered and agreed on in its handshake, to redo it
+/// over a new connection after the old one dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeRecord {
+    offered: Vec<String>,
+    content_type: Option<String>,
+}
+
+impl HandshakeRecord {
+    /// Send the same READY to `stream` and, if the peer accepts the
+    /// content type agreed before, the same START. Fail rather than
+    /// settle on another type.
+    pub fn replay<S: Read + Write>(&self, stream: &mut S) -> Result<()> {
+        let accepted = send_ready(stream, &self.offered)?;
+        if let Some(typ) = &self.content_type {
+            if !accepted.contains(typ) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "content type {:?} no longer accepted, only {:?}",
+                        typ, accepted
+                    ),
+                ));
+            }
+        }
+        send_start(stream, self.content_type.clone())
+    }
+}
+
+/// Write READY offering `offered`, return content types of the ACCEPT.
+fn send_ready<S: Read + Write>(stream: &mut S, offered: &[String]) -> Result<Vec<String>> {
+    let ready = ControlFrame::new(ControlType::Ready, offered.iter().cloned());
+    write_control_frame(stream, &ready)?;
+    stream.flush()?;
+
+    let accept = reader::reader(stream).read_control_frame()?;
+    accept.assert_type(ControlType::Accept)?;
+    Ok(accept
+        .content_types()
+        .into_iter()
+        .map(String::from)
+        .collect())
+}
+
+fn send_start<S: Write>(stream: &mut S, content_type: Option<String>) -> Result<()> {
+    let start = ControlFrame::new(ControlType::Start, content_type);
+    write_control_frame(stream, &start)
+}
+
+impl<S: Read + Write> Client<S> {
+    /// Offer `content_types` in READY, in order of preference, and START
+    /// with the first one the peer accepts. Fail if it accepts none.
+    pub fn connect<T>(mut stream: S, content_types: T) -> Result<Self>
+    where
+        T: IntoIterator<Item = String>,
+    {
+        let offered: Vec<String> = content_types.into_iter().collect();
+        let accepted = send_ready(&mut stream, &offered)?;
+        let content_type = match offered.iter().find(|typ| accepted.contains(typ)) {
+            Some(typ) => Some(typ.clone()),
+            None if offered.is_empty() => None,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "content types mismatched: offered {:?}, accepted {:?}",
+                        offered, accepted
+                    ),
+                ))
+            }
+        };
+
+        send_start(&mut stream, content_type.clone())?;
+        Ok(Self {
+            stream,
+            handshake: HandshakeRecord {
+                offered,
+                content_type,
+            },
+        })
+    }
+
+    /// Redo a handshake recorded by another client over `stream`, see
+    /// `HandshakeRecord::replay`.
+    pub fn reconnect(mut stream: S, handshake: &HandshakeRecord) -> Result<Self> {
+        handshake.replay(&mut stream)?;
+        Ok(Self {
+            stream,
+            handshake: handshake.clone(),
+        })
+    }
+
+    /// Content type agreed with the peer, None if nothing was offered.
+    pub fn content_type(&self) -> Option<&str> {
+        self.handshake.content_type.as_deref()
+    }
+
+    /// The handshake done, for `reconnect` to redo it.
+    pub fn handshake(&self) -> &HandshakeRecord {
+        &self.handshake
+    }
+
+    /// Write one data frame. `data` must not be empty.
+    pub fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        write_data_frame(&mut self.stream, data)
+    }
+
+    /// Read a frame the peer wrote back, for peers replying with data
+    /// frames (see `FstrmReader::write_frame`).
+    pub fn read_frame(&mut self) -> Result<Frame> {
+        Frame::decode(&mut self.stream)
+    }
+
+    /// Send STOP, wait for FINISH, and return the underlying stream.
+    pub fn finish(mut self) -> Result<S> {
+        write_stop(&mut self.stream, None)?;
+        self.stream.flush()?;
+        let finish = reader::reader(&mut self.stream).read_control_frame()?;
+        finish.assert_type(ControlType::Finish)?;
+        Ok(self.stream)
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Result, Write},
+    ops::Range,
+    os::unix::fs::FileExt,
+    sync::{Arc, Mutex},
+};
+
+/// Coordinator handing out disjoint regions of one pre-allocated file,
+/// so that threads may each write a stream into it without sharing the
+/// file cursor.
+///
+/// Unused space at the end of a region is left as zeros, which a reader
+/// would take for a control frame; decode each region on its own, e.g.
+/// by sizing regions with `planned_stream_size`.
+pub struct Regions {
+    file: Arc<File>,
+    len: u64,
+    next: Mutex<u64>,
+}
+
+impl Regions {
+    /// Pre-allocate `file` to `len` bytes.
+    pub fn new(file: File, len: u64) -> Result<Self> {
+        file.set_len(len)?;
+        Ok(Self {
+            file: Arc::new(file),
+            len,
+            next: Mutex::new(0),
+        })
+    }
+
+    /// Take the next `len` bytes of the file. Fail if they are beyond
+    /// its pre-allocated length.
+    pub fn region(&self, len: u64) -> Result<RegionWriter> {
+        let mut next = self.next.lock().unwrap();
+        let end = next
+            .checked_add(len)
+            .filter(|end| *end <= self.len)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no room for region"))?;
+        let writer = RegionWriter {
+            file: self.file.clone(),
+            start: *next,
+            pos: *next,
+            end,
+        };
+        *next = end;
+        Ok(writer)
+    }
+}
+
+/// Writer of one region of a file, with positional writes.
+///
+/// Writes beyond the region fail with `WriteZero`. Nothing is buffered,
+/// so `flush` does nothing.
+pub struct RegionWriter {
+    file: Arc<File>,
+    start: u64,
+    pos: u64,
+    end: u64,
+}
+
+impl RegionWriter {
+    /// Offsets of the region in the file.
+    pub fn range(&self) -> Range<u64> {
+        self.start..self.end
+    }
+
+    /// Bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.pos - self.start
+    }
+}
+
+impl Write for RegionWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let room = (self.end - self.pos).min(usize::MAX as u64) as usize;
+        let buf = &buf[..buf.len().min(room)];
+        self.file.write_all_at(buf, self.pos)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
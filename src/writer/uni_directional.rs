@@ -0,0 +1,43 @@
+use super::{write_control_frame, write_data_frame, write_stop};
+use crate::reader::{ControlFrame, ControlType};
+use std::io::{Result, Write};
+
+/// Writer of a uni-directional stream, e.g. a capture file.
+///
+/// START is written on creation and STOP by `finish()`, or on drop as a
+/// last resort, where errors cannot be reported.
+pub struct Writer<W: Write> {
+    writer: Option<W>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Write START with given content type.
+    pub fn new(mut writer: W, content_type: &str) -> Result<Self> {
+        let start = ControlFrame::new(ControlType::Start, Some(content_type.to_string()));
+        write_control_frame(&mut writer, &start)?;
+        Ok(Self {
+            writer: Some(writer),
+        })
+    }
+
+    /// Write one data frame. `data` must not be empty.
+    pub fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        write_data_frame(self.writer.as_mut().unwrap(), data)
+    }
+
+    /// Write STOP, flush, and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        let mut writer = self.writer.take().unwrap();
+        write_stop(&mut writer, None)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Drop for Writer<W> {
+    fn drop(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            let _ = write_stop(&mut writer, None).and_then(|_| writer.flush());
+        }
+    }
+}